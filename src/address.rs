@@ -1,26 +1,86 @@
-use crate::Line;
-use std::sync::atomic;
+use crate::{Line, RegexOptions};
+use anyhow::Result;
+use std::{collections::BTreeSet, sync::atomic};
 
-#[derive(Debug, PartialEq)]
-pub(crate) enum Address {
-    // always matches
+/// Address selecting the lines the commands run on.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum Address {
+    /// always matches
     Always,
-    // never matches
+    /// never matches
     Final,
-    // specific index
+    /// specific index
     Location(usize),
-    // /regex/ matching the line
+    /// /regex/ matching the line
     Regex(crate::Regex),
-    // !addr negates the addr match
+    /// !addr negates the addr match
     Negate(Box<Address>),
-    // // addr1 - addr2
+    /// addr1 - addr2
     Between(Between),
-    // addr1, addr2, ...
+    /// addr1, addr2, ... matches if any of the addresses matches (logical OR),
+    /// so a negated member like in !10,5 matches all the lines except 10
     Set(Vec<Address>),
-    // _
+    /// /regex1/, /regex2/, ... set of regexes combined into a single alternation,
+    /// since matching one regex is faster than trying them one by one,
+    /// it is displayed as the original set
+    Fused(crate::Regex, Vec<Address>),
+    /// n1, n2, ... set of line numbers, it takes memory proportional to
+    /// the number of lines listed, but the lookup is O(log n)
+    Lines(BTreeSet<usize>),
+    /// field(N)/regex/ or field(N -dC)/regex/, regex matching the Nth (0-based)
+    /// field split on whitespace or the separator
+    FieldRegex(usize, Option<char>, crate::Regex),
+    /// len==N, len<N, len>N, len<=N, or len>=N, comparing the number of characters
+    /// of the line with N
+    Length(Comparison, usize),
+    /// ?, replaced by the regex of the substitution following it when parsing,
+    /// so it cannot be used in [`Program::from_actions`](crate::Program::from_actions)
     Maybe,
 }
 
+/// Comparison operator of the [`Address::Length`] address.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    /// ==
+    Equal,
+    /// <
+    Less,
+    /// >
+    Greater,
+    /// <=
+    LessOrEqual,
+    /// >=
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    fn compare(&self, lhs: usize, rhs: usize) -> bool {
+        use Comparison::*;
+        match self {
+            Equal => lhs == rhs,
+            Less => lhs < rhs,
+            Greater => lhs > rhs,
+            LessOrEqual => lhs <= rhs,
+            GreaterOrEqual => lhs >= rhs,
+        }
+    }
+}
+
+impl std::fmt::Display for Comparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use Comparison::*;
+        let op = match self {
+            Equal => "==",
+            Less => "<",
+            Greater => ">",
+            LessOrEqual => "<=",
+            GreaterOrEqual => ">=",
+        };
+        write!(f, "{op}")
+    }
+}
+
 impl Address {
     pub(crate) fn matches(&self, line: &Line) -> bool {
         use Address::*;
@@ -28,7 +88,7 @@ impl Address {
             Always => true,
             Final => false,
             Location(idx) => *idx == line.0,
-            Regex(regex) => regex.0.is_match(&line.1),
+            Regex(regex) | Fused(regex, _) => regex.0.is_match(&line.1),
             Negate(addr) => !addr.matches(line),
             Between(this) => this.matches(line),
             Set(set) => {
@@ -39,20 +99,105 @@ impl Address {
                 }
                 false
             }
-            Maybe => unimplemented!(),
+            Lines(set) => set.contains(&line.0),
+            FieldRegex(idx, sep, regex) => {
+                let field = match sep {
+                    Some(sep) => line.1.split(*sep).nth(*idx),
+                    None => line.1.split_whitespace().nth(*idx),
+                };
+                field.is_some_and(|field| regex.0.is_match(field))
+            }
+            Length(op, len) => op.compare(line.1.chars().count(), *len),
+            // rejected by `Program::from_actions`, the parser replaces it
+            Maybe => unreachable!(),
+        }
+    }
+
+    /// Is it, or any of its parts, the `?` placeholder, which only the parser can replace?
+    pub(crate) fn has_placeholder(&self) -> bool {
+        use Address::*;
+        match self {
+            Maybe => true,
+            Negate(addr) => addr.has_placeholder(),
+            Between(between) => between.lhs.has_placeholder() || between.rhs.has_placeholder(),
+            Set(set) | Fused(_, set) => set.iter().any(Address::has_placeholder),
+            _ => false,
+        }
+    }
+
+    /// Compile the regular expressions again using the `options`.
+    pub(crate) fn set_regex_options(&mut self, options: RegexOptions) -> Result<()> {
+        use Address::*;
+        match self {
+            Regex(regex) | FieldRegex(_, _, regex) => *regex = regex.with_options(options)?,
+            Fused(regex, set) => {
+                *regex = regex.with_options(options)?;
+                for addr in set.iter_mut() {
+                    addr.set_regex_options(options)?;
+                }
+            }
+            Negate(addr) => addr.set_regex_options(options)?,
+            Between(this) => {
+                this.lhs.set_regex_options(options)?;
+                this.rhs.set_regex_options(options)?;
+            }
+            Set(set) => {
+                for addr in set.iter_mut() {
+                    addr.set_regex_options(options)?;
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Reset the state of all the ranges, so they are outside of the range.
+    pub(crate) fn reset(&self) {
+        use Address::*;
+        match self {
+            Negate(addr) => addr.reset(),
+            Between(this) => this.reset(),
+            Set(set) => set.iter().for_each(Address::reset),
+            _ => (),
+        }
+    }
+
+    /// Does the address, or any of the members of the set, use a regular expression?
+    pub(crate) fn has_regex(&self) -> bool {
+        use Address::*;
+        match self {
+            Regex(_) | Fused(..) => true,
+            Set(set) => set.iter().any(|addr| matches!(addr, Regex(_))),
+            _ => false,
+        }
+    }
+
+    /// The regular expression of the address that matched the `line`, for a set it is
+    /// the first of the regular expressions in it that matches the line.
+    pub(crate) fn matched_regex(&self, line: &Line) -> Option<&crate::Regex> {
+        use Address::*;
+        match self {
+            Regex(regex) | Fused(regex, _) => Some(regex),
+            // the other members are not evaluated again, since the ranges keep state
+            Set(set) => set.iter().find_map(|addr| match addr {
+                Regex(regex) if regex.0.is_match(&line.1) => Some(regex),
+                _ => None,
+            }),
+            _ => None,
         }
     }
 }
 
+/// Range of the lines starting at the line matching `lhs` and ending at the one matching `rhs`.
 #[derive(Debug)]
-pub(crate) struct Between {
+pub struct Between {
     pub(crate) lhs: Box<Address>,
     pub(crate) rhs: Box<Address>,
     inside: atomic::AtomicBool,
 }
 
 impl Between {
-    pub(crate) fn new(lhs: Address, rhs: Address) -> Self {
+    pub fn new(lhs: Address, rhs: Address) -> Self {
         Between {
             lhs: Box::new(lhs),
             rhs: Box::new(rhs),
@@ -60,6 +205,22 @@ impl Between {
         }
     }
 
+    /// The address starting the range.
+    pub fn lhs(&self) -> &Address {
+        &self.lhs
+    }
+
+    /// The address ending the range.
+    pub fn rhs(&self) -> &Address {
+        &self.rhs
+    }
+
+    pub(crate) fn reset(&self) {
+        self.inside.store(false, atomic::Ordering::Relaxed);
+        self.lhs.reset();
+        self.rhs.reset();
+    }
+
     pub(crate) fn matches(&self, line: &Line) -> bool {
         if self.inside.load(atomic::Ordering::Relaxed) {
             if self.rhs.matches(line) {
@@ -78,6 +239,17 @@ impl Between {
     }
 }
 
+/// The clone has its own state, so it can be used independently, e.g. by another thread.
+impl Clone for Between {
+    fn clone(&self) -> Self {
+        Between {
+            lhs: self.lhs.clone(),
+            rhs: self.rhs.clone(),
+            inside: atomic::AtomicBool::new(self.inside.load(atomic::Ordering::Relaxed)),
+        }
+    }
+}
+
 impl PartialEq for Between {
     fn eq(&self, other: &Self) -> bool {
         self.lhs == other.lhs && self.rhs == other.rhs
@@ -104,9 +276,12 @@ impl std::fmt::Display for Address {
             Final => write!(f, "$"),
             Location(idx) => write!(f, "{idx}"),
             Regex(regex) => write!(f, "/{regex}/"),
-            Negate(addr) => write!(f, "{addr}!"),
+            Negate(addr) => match addr.as_ref() {
+                Between(_) | Set(_) | Fused(..) | Lines(_) => write!(f, "!({addr})"),
+                _ => write!(f, "!{addr}"),
+            },
             Between(this) => write!(f, "{}-{}", this.lhs, this.rhs),
-            Set(addrs) => {
+            Set(addrs) | Fused(_, addrs) => {
                 let list = addrs
                     .iter()
                     .map(|a| a.to_string())
@@ -114,6 +289,17 @@ impl std::fmt::Display for Address {
                     .join(", ");
                 write!(f, "{list}")
             }
+            Lines(set) => {
+                let list = set
+                    .iter()
+                    .map(|a| a.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                write!(f, "{list}")
+            }
+            FieldRegex(idx, None, regex) => write!(f, "field({})/{regex}/", idx + 1),
+            FieldRegex(idx, Some(sep), regex) => write!(f, "field({} -d{sep})/{regex}/", idx + 1),
+            Length(op, len) => write!(f, "len{op}{len}"),
             Maybe => write!(f, "?"),
         }
     }
@@ -129,40 +315,108 @@ mod tests {
     use std::str::FromStr;
     use test_case::test_case;
 
-    #[test_case(Always, Line(1, "".to_string()), true; "any matches line 1")]
-    #[test_case(Always, Line(279, "".to_string()), true; "any matches line 279")]
-    #[test_case(Negate(Box::new(Always)), Line(1, "".to_string()), false; "negated any does not match line 1")]
-    #[test_case(Negate(Box::new(Always)), Line(279, "".to_string()), false; "negated any does not match line 279")]
-    #[test_case(Location(1), Line(1, "".to_string()), true; "index 1 matches line 1")]
-    #[test_case(Location(1), Line(279, "".to_string()), false; "index 1 does not match line 279")]
+    #[test_case(Always, Line(1, "".to_string(), None), true; "any matches line 1")]
+    #[test_case(Always, Line(279, "".to_string(), None), true; "any matches line 279")]
+    #[test_case(Negate(Box::new(Always)), Line(1, "".to_string(), None), false; "negated any does not match line 1")]
+    #[test_case(Negate(Box::new(Always)), Line(279, "".to_string(), None), false; "negated any does not match line 279")]
+    #[test_case(Location(1), Line(1, "".to_string(), None), true; "index 1 matches line 1")]
+    #[test_case(Location(1), Line(279, "".to_string(), None), false; "index 1 does not match line 279")]
     #[test_case(
         Regex(crate::Regex::from_str("abc").unwrap()),
-        Line(1, "abc".to_string()),
+        Line(1, "abc".to_string(), None),
         true;
         "regex abc matches line abc"
     )]
     #[test_case(
         Regex(crate::Regex::from_str("abc").unwrap()),
-        Line(1, "hello, world!".to_string()),
+        Line(1, "hello, world!".to_string(), None),
         false;
         "regex abc does not match line hello"
     )]
     #[test_case(
         Set(vec![Location(1), Location(2), Location(3)]),
-        Line(1, "".to_string()),
+        Line(1, "".to_string(), None),
         true;
         "set 1,2,3 matches line 1"
     )]
     #[test_case(
         Set(vec![Location(1), Location(2), Location(3)]),
-        Line(279, "".to_string()),
+        Line(279, "".to_string(), None),
         false;
         "set 1,2,3 does not match line 279"
     )]
+    #[test_case(
+        Set(vec![Negate(Box::new(Location(10))), Location(5)]),
+        Line(10, "".to_string(), None),
+        false;
+        "set !10,5 does not match line 10"
+    )]
+    #[test_case(
+        Set(vec![Negate(Box::new(Location(10))), Location(5)]),
+        Line(5, "".to_string(), None),
+        true;
+        "set !10,5 matches line 5"
+    )]
+    #[test_case(
+        Set(vec![Negate(Box::new(Location(10))), Location(5)]),
+        Line(1, "".to_string(), None),
+        true;
+        "set !10,5 matches line 1"
+    )]
     fn matches(addr: Address, line: Line, expected: bool) {
         assert_eq!(addr.matches(&line), expected)
     }
 
+    #[test_case("len==3", "abc", true; "equal")]
+    #[test_case("len==3", "abcd", false; "not equal")]
+    #[test_case("len<3", "ab", true; "less")]
+    #[test_case("len<3", "abc", false; "less at the bound")]
+    #[test_case("len>3", "abcd", true; "greater")]
+    #[test_case("len>3", "abc", false; "greater at the bound")]
+    #[test_case("len<=3", "abc", true; "less or equal at the bound")]
+    #[test_case("len<=3", "abcd", false; "less or equal above the bound")]
+    #[test_case("len>=3", "abc", true; "greater or equal at the bound")]
+    #[test_case("len>=3", "ab", false; "greater or equal below the bound")]
+    #[test_case("len==0", "", true; "empty line")]
+    #[test_case("len==5", "zażół", true; "characters rather than bytes")]
+    #[test_case("!len>3", "abcd", false; "negated")]
+    fn length(addr: &str, line: &str, expected: bool) {
+        let mut reader = StringReader::from(addr);
+        let addr = crate::parser::address::parse(&mut reader, &mut Default::default()).unwrap();
+        assert_eq!(addr.matches(&Line(1, line.to_string(), None)), expected)
+    }
+
+    #[test_case("!10,5", "!10, 5"; "negated member")]
+    #[test_case("!(2,3,4)", "!(2, 3, 4)"; "negated set")]
+    #[test_case("!2-5", "!(2-5)"; "negated range")]
+    #[test_case("!(/a/,/b/)", "!(/a/, /b/)"; "negated regexes")]
+    fn display(input: &str, expected: &str) {
+        let addr =
+            crate::parser::address::parse(&mut StringReader::from(input), &mut Default::default())
+                .unwrap();
+        assert_eq!(addr.to_string(), expected);
+        let reparsed = crate::parser::address::parse(
+            &mut StringReader::from(expected),
+            &mut Default::default(),
+        )
+        .unwrap();
+        assert_eq!(reparsed, addr);
+    }
+
+    #[test_case("^abc$", "abc\ndef", false; "whole line does not match the first line")]
+    #[test_case("^def$", "abc\ndef", false; "whole line does not match the last line")]
+    #[test_case("^def$", "def\n", false; "whole line does not match before the trailing newline")]
+    #[test_case(r"^abc\ndef$", "abc\ndef", true; "whole line matches all the lines")]
+    #[test_case("^(?m)def$", "abc\ndef", false; "whole line ignores multiline flag")]
+    #[test_case(r"^(?m)abc\n^def$", "abc\ndef", true; "whole line with multiline flag inside")]
+    #[test_case("/(?m)^def$/", "abc\ndef", true; "regex with multiline flag")]
+    fn multiline_pattern_space(addr: &str, text: &str, expected: bool) {
+        let addr =
+            crate::parser::address::parse(&mut StringReader::from(addr), &mut Default::default())
+                .unwrap();
+        assert_eq!(addr.matches(&Line(1, text.to_string(), None)), expected);
+    }
+
     #[test_case(
         "//",
         vec![true, true, true, true, true, true, true, true, true, true];
@@ -218,6 +472,46 @@ mod tests {
         vec![false, false, false, false, true, true, true, true, true, false];
         "mixed range"
     )]
+    #[test_case(
+        "9,1,3,5",
+        vec![true, false, true, false, true, false, false, false, true, false];
+        "line numbers"
+    )]
+    #[test_case(
+        "field(2)/^a/",
+        vec![false, false, false, false, true, true, false, false, false, false];
+        "field"
+    )]
+    #[test_case(
+        "field(2 -da)/^$/",
+        vec![false, false, true, false, true, false, false, false, false, false];
+        "field with separator"
+    )]
+    #[test_case(
+        "field(4)/.*/",
+        vec![false, false, false, false, true, false, false, false, false, false];
+        "missing field"
+    )]
+    #[test_case(
+        "!10,5",
+        vec![true, true, true, true, true, true, true, true, true, false];
+        "negated member of set"
+    )]
+    #[test_case(
+        "!2,!3",
+        vec![true, true, true, true, true, true, true, true, true, true];
+        "negated members of set"
+    )]
+    #[test_case(
+        "!(2,3),5",
+        vec![true, false, false, true, true, true, true, true, true, true];
+        "negated set in set"
+    )]
+    #[test_case(
+        "!/aa/,/start/",
+        vec![true, true, false, true, false, true, true, true, true, true];
+        "negated regex in set"
+    )]
     #[test_case(
         "6-$",
         vec![false, false, false, false, false, true, true, true, true, true];
@@ -235,13 +529,13 @@ mod tests {
             123
         ";
         let mut reader = StringReader::from(addr);
-        let addr = crate::parser::address::parse(&mut reader).unwrap();
+        let addr = crate::parser::address::parse(&mut reader, &mut Default::default()).unwrap();
         assert_eq!(
             example
                 .lines()
                 .enumerate()
                 .map(|(i, s)| {
-                    let line = Line(i + 1, s.to_string());
+                    let line = Line(i + 1, s.to_string(), None);
                     addr.matches(&line)
                 })
                 .collect::<Vec<bool>>(),
@@ -1,31 +1,75 @@
-use crate::{Line, Regex, program::Memory};
-use anyhow::Result;
-use std::io::{StdoutLock, Write};
+use crate::{Line, Regex, RegexOptions, address::Address, program::Memory};
+use anyhow::{Result, anyhow, bail};
+use regex::Captures;
+use std::{
+    borrow::Cow,
+    io::{Read, Write},
+    path::PathBuf,
+    process::{Child, ExitStatus, Stdio},
+    sync::{Arc, Mutex},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
 
-#[derive(Debug, PartialEq)]
-pub(crate) enum Command {
+/// Command run on the lines matched by the address.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum Command {
     /// p
     Println,
-    /// P
-    Print,
-    /// l
-    Escapeln,
-    /// =
-    LineNumber,
-    /// "string" or 'string'
+    /// P or P-, the former prints the pattern space up to the first newline followed
+    /// by the newline, the latter all of it without the newline
+    Print(bool),
+    /// l [width]
+    Escapeln(Option<usize>),
+    /// = or =-, the latter does not print the newline after the number
+    LineNumber(bool),
+    /// n [width] [-s'sep'], prepends the line number to the pattern space
+    NumberPrefix(usize, String),
+    /// F
+    PrintFilename,
+    /// "string" or 'string', `$$` stands for a literal dollar
     Insert(String),
-    /// s/src/dst/[limit]
-    Substitute(Regex, String, usize),
-    /// k s-e
-    Keep(usize, Option<usize>),
+    /// s/src/dst/[limit], or s/src/dst/Ng, where the limit is the first replaced match
+    /// and the following ones are replaced as well
+    Substitute {
+        regex: Regex,
+        replacement: String,
+        /// the number of the replaced matches, 0 for all of them
+        limit: usize,
+        /// replace all the matches starting from the `limit`th one
+        global: bool,
+    },
+    /// k s-e or kb s-e, keeps the characters (or bytes) in the [start, end) range
+    Keep(Position, Option<Position>, bool),
+    /// c N,M,... or c N,M,... -d:, keeps the fields split on whitespace or the separator
+    Field(Vec<usize>, Option<char>),
+    /// S or S/c/
+    Squeeze(Option<char>),
+    /// E [width]
+    Expand(usize),
+    /// ~
+    Trim,
+    /// ~^
+    TrimStart,
+    /// ~$
+    TrimEnd,
+    /// v
+    Reverse,
+    /// U
+    Upper,
+    /// L
+    Lower,
     /// &
     GetLine,
-    /// h
-    Hold,
-    /// g
-    Get,
-    /// x
-    Exchange,
+    /// h or h:name
+    Hold(Option<String>),
+    /// H
+    HoldAppend,
+    /// g or g:name
+    Get(Option<String>),
+    /// x or x:name
+    Exchange(Option<String>),
     /// j
     Joinln,
     /// J
@@ -34,24 +78,120 @@ pub(crate) enum Command {
     Readln(usize),
     /// R
     ReadReplace,
+    /// r'file'
+    ReadFile(PathBuf),
+    /// w'file'
+    WriteFile(PathBuf),
     /// z
     Reset,
     /// d
     Delete,
+    /// B
+    SqueezeBlank,
+    /// u or u N,M,... [-dC], compares the fields like `c` rather than the whole pattern space
+    Uniq(Vec<usize>, Option<char>),
     /// .
     Break,
+    /// t
+    BranchIfSub,
+    /// T
+    BranchIfNoSub,
     /// q [code]
     Quit(i32),
-    /// e
-    Eval,
+    /// Q [code]
+    QuitSilent(i32),
+    /// e or e'command', with `!` the exit code is ignored,
+    /// with `+` the trailing newline of the output is kept
+    Eval {
+        command: Option<String>,
+        ignore_status: bool,
+        keep_newline: bool,
+    },
+    /// @name
+    Call(String),
 }
 
-#[derive(Debug, PartialEq)]
+/// Zero-based character position used by the `k` command.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Position {
+    /// counted from the start
+    Start(usize),
+    /// counted from the end, `End(1)` is the position of the final character
+    End(usize),
+}
+
+impl Position {
+    fn is_from_end(&self) -> bool {
+        matches!(self, Position::End(_))
+    }
+
+    fn resolve(&self, len: usize) -> usize {
+        match self {
+            Position::Start(pos) => *pos,
+            Position::End(pos) => len.saturating_sub(*pos),
+        }
+    }
+}
+
+/// Default line-wrap width for the `l` command.
+pub(crate) const LINE_WRAP: usize = 70;
+
+/// Default tab width for the `E` command.
+pub(crate) const TAB_WIDTH: usize = 8;
+
+/// Default width of the line numbers for the `n` command, same as in `cat -n`.
+pub(crate) const NUMBER_WIDTH: usize = 6;
+
+/// Result of processing a line or of running the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Status {
     Normal,
     Break,
     NoPrint,
+    /// Quit without running the finally block
     Quit(i32),
+    /// Quit without printing the line and running the finally block
+    QuitSilent(i32),
+}
+
+impl Command {
+    /// Compile the regular expressions again using the `options`.
+    pub(crate) fn set_regex_options(&mut self, options: RegexOptions) -> Result<()> {
+        if let Command::Substitute { regex, .. } = self {
+            *regex = regex.with_options(options)?;
+        }
+        Ok(())
+    }
+}
+
+impl Status {
+    /// Exit code if the status stops the program.
+    pub fn exit_code(&self) -> Option<i32> {
+        match self {
+            Status::Quit(code) | Status::QuitSilent(code) => Some(*code),
+            _ => None,
+        }
+    }
+
+    /// The program was stopped by `q` or `Q`.
+    ///
+    /// ```
+    /// use se::{Program, Status};
+    /// use std::str::FromStr;
+    ///
+    /// let mut program = Program::from_str("/stop/ q3").unwrap();
+    /// let (_, status) = program.run_str("go\nstop\ngo", true).unwrap();
+    /// assert!(status.is_quit());
+    /// assert_eq!(status.exit_code(), Some(3));
+    /// ```
+    pub fn is_quit(&self) -> bool {
+        self.exit_code().is_some()
+    }
+
+    /// The line was not printed by the auto-print, because it was deleted or `Q` was used.
+    pub fn is_deleted(&self) -> bool {
+        matches!(self, Status::NoPrint | Status::QuitSilent(_))
+    }
 }
 
 impl From<&Command> for Status {
@@ -60,6 +200,7 @@ impl From<&Command> for Status {
             Command::Delete => Status::NoPrint,
             Command::Break => Status::Break,
             Command::Quit(code) => Status::Quit(*code),
+            Command::QuitSilent(code) => Status::QuitSilent(*code),
             _ => Status::Normal,
         }
     }
@@ -68,45 +209,142 @@ impl From<&Command> for Status {
 impl Command {
     /// Run the command by modifying one of the `pattern` or `hold` buffers
     /// and returning a status code.
-    pub(crate) fn run<R: Iterator<Item = Result<Line>>>(
+    pub(crate) fn run<R: Iterator<Item = Result<Line>>, W: Write>(
         &self,
         memory: &mut Memory,
+        matched: Option<&Address>,
         reader: &mut R,
-        out: &mut StdoutLock,
+        out: &mut W,
     ) -> Result<Status> {
         use Command::*;
         match self {
             // commands that print things
-            Println => writeln!(out, "{}", memory.this)?,
-            Print => write!(out, "{}", memory.this)?,
-            Escapeln => {
-                let escaped = memory.this.escape_default().to_string();
+            Print(true) if memory.json => write_json(out, memory.line.0, first_line(&memory.this))?,
+            Println | Print(_) | Escapeln(_) if memory.json => {
+                write_json(out, memory.line.0, &memory.this)?
+            }
+            LineNumber(_) if memory.json => (),
+            PrintFilename if memory.json => {
+                let name = memory.line.2.as_ref().map(|path| path.to_string_lossy());
+                write_json(out, memory.line.0, name.as_deref().unwrap_or("-"))?
+            }
+            Insert(message) if memory.json => {
+                let captures = memory.captures(matched);
+                let env = memory.expand_env.then_some(&env_var as Lookup);
+                let message = expand(message, env, captures.as_ref());
+                let message = message.strip_suffix('\n').unwrap_or(&message);
+                write_json(out, memory.line.0, message)?
+            }
+            Println => write!(
+                out,
+                "{}{}",
+                memory.colored(matched, &memory.this),
+                memory.separator()
+            )?,
+            Print(true) => {
+                let first = first_line(&memory.this);
+                write!(
+                    out,
+                    "{}{}",
+                    memory.colored(matched, first),
+                    memory.separator()
+                )?
+            }
+            Print(false) => write!(out, "{}", memory.colored(matched, &memory.this))?,
+            Escapeln(width) => {
+                let escaped = escape(&memory.this, width.unwrap_or(LINE_WRAP), memory.binary);
                 writeln!(out, "{escaped}")?
             }
-            LineNumber => write!(out, "{}", memory.line.0)?,
-            Insert(message) => write!(out, "{message}")?,
+            LineNumber(newline) => {
+                write!(out, "{}", memory.line.0)?;
+                if *newline {
+                    write!(out, "{}", memory.separator())?
+                }
+            }
+            PrintFilename => match &memory.line.2 {
+                Some(path) => writeln!(out, "{}", path.display())?,
+                None => writeln!(out, "-")?,
+            },
+            Insert(message) => {
+                let captures = memory.captures(matched);
+                let env = memory.expand_env.then_some(&env_var as Lookup);
+                write!(out, "{}", expand(message, env, captures.as_ref()))?
+            }
             // commands that modify the buffers
-            Substitute(regex, template, limit) => {
-                let replaced = regex.0.replacen(&memory.this, *limit, template);
-                memory.this = replaced.to_string()
+            Substitute {
+                regex,
+                replacement,
+                limit,
+                global,
+            } => {
+                // `Ng` replaces all the matches starting from the Nth one, plain `N` the first N
+                let (skip, limit) = if *global {
+                    (limit.saturating_sub(1), 0)
+                } else {
+                    (0, *limit)
+                };
+                // when nothing matched, the pattern space is left as-is rather than copied,
+                // otherwise the result is written to the scratch buffer that is swapped with it
+                if replace_into(
+                    &regex.0,
+                    &memory.this,
+                    skip,
+                    limit,
+                    replacement,
+                    &mut memory.scratch,
+                ) {
+                    std::mem::swap(&mut memory.this, &mut memory.scratch);
+                    memory.substituted = true;
+                }
+            }
+            Keep(start, end, true) => {
+                let len = memory.this.len();
+                let end = end.map_or(len, |e| e.resolve(len));
+                memory.this = keep_bytes(&memory.this, start.resolve(len), end).to_string();
             }
-            Keep(skip, take) => {
-                memory.this = if let Some(take) = take {
-                    memory.this.chars().skip(*skip).take(*take).collect()
+            Keep(start, end, false) => {
+                let len = if start.is_from_end() || end.is_some_and(|e| e.is_from_end()) {
+                    memory.this.chars().count()
                 } else {
-                    memory.this.chars().skip(*skip).collect()
+                    0
                 };
+                let skip = start.resolve(len);
+                memory.this = if let Some(end) = end {
+                    let take = end.resolve(len).saturating_sub(skip);
+                    memory.this.chars().skip(skip).take(take).collect()
+                } else {
+                    memory.this.chars().skip(skip).collect()
+                };
+            }
+            Field(indices, sep) => memory.this = fields(&memory.this, indices, *sep),
+            Squeeze(c) => memory.this = squeeze(&memory.this, *c),
+            Expand(width) => memory.this = expand_tabs(&memory.this, *width),
+            NumberPrefix(width, sep) => {
+                let prefix = format!("{:>width$}{sep}", memory.line.0);
+                memory.this.insert_str(0, &prefix);
             }
+            Trim => memory.this = memory.this.trim().to_string(),
+            TrimStart => memory.this = memory.this.trim_start().to_string(),
+            TrimEnd => memory.this.truncate(memory.this.trim_end().len()),
+            Reverse => memory.this = memory.this.chars().rev().collect(),
+            Upper => memory.this = memory.this.to_uppercase(),
+            Lower => memory.this = memory.this.to_lowercase(),
             Reset => memory.this.clear(),
-            Hold => {
-                memory.hold = memory.this.to_string();
+            Hold(name) => {
+                *memory.register(name) = memory.this.to_string();
             }
-            Get => {
-                memory.this = memory.hold.to_string();
+            HoldAppend => {
+                memory.hold.push('\n');
+                memory.hold.push_str(&memory.this);
+            }
+            Get(name) => {
+                memory.this = memory.register(name).to_string();
             }
             GetLine => memory.this = memory.line.1.to_string(),
-            Exchange => {
-                std::mem::swap(&mut memory.hold, &mut memory.this);
+            Exchange(name) => {
+                let mut this = std::mem::take(&mut memory.this);
+                std::mem::swap(memory.register(name), &mut this);
+                memory.this = this;
             }
             Joinln => {
                 memory.this.push('\n');
@@ -118,13 +356,20 @@ impl Command {
             Readln(n) => {
                 for _ in 0..*n {
                     if let Some(line) = reader.next() {
+                        let line = line?;
                         memory.this.push('\n');
-                        memory.this.push_str(&line?.1);
+                        memory.this.push_str(&line.1);
+                        memory.line = line;
                     } else {
+                        // unlike for `R`, the following commands still run
+                        // on the lines that were accumulated
                         break;
                     }
                 }
             }
+            ReadFile(path) => memory.queue.push(path.clone()),
+            WriteFile(path) => memory.write_file(path, matched, out)?,
+            Call(name) => memory.this = memory.callbacks.call(name, &memory.this)?,
             ReadReplace => {
                 if let Some(line) = reader.next() {
                     memory.read(line?);
@@ -137,11 +382,55 @@ impl Command {
                 memory.this.clear();
                 return Ok(Status::NoPrint);
             }
-            Break | Quit(_) => return Ok(Status::from(self)),
-            Eval => {
-                let (stdout, code) = eval_sh(&memory.this)?;
+            SqueezeBlank => {
+                let blank = memory.this.is_empty();
+                if blank && memory.blank {
+                    return Ok(Status::NoPrint);
+                }
+                memory.blank = blank;
+            }
+            Uniq(indices, sep) => {
+                let key = if indices.is_empty() {
+                    memory.this.clone()
+                } else {
+                    fields(&memory.this, indices, *sep)
+                };
+                if memory.uniq.as_ref() == Some(&key) {
+                    return Ok(Status::NoPrint);
+                }
+                memory.uniq = Some(key);
+            }
+            Break | Quit(_) | QuitSilent(_) => return Ok(Status::from(self)),
+            BranchIfSub => {
+                if memory.substituted {
+                    memory.substituted = false;
+                    return Ok(Status::Break);
+                }
+            }
+            BranchIfNoSub => {
+                if !memory.substituted {
+                    return Ok(Status::Break);
+                }
+                memory.substituted = false;
+            }
+            Eval {
+                command,
+                ignore_status,
+                keep_newline,
+            } => {
+                let timeout = eval_timeout(std::env::var("SE_EVAL_TIMEOUT").ok().as_deref())?;
+                let (stdout, code) = match command {
+                    Some(cmd) => eval_sh(cmd, Some(&memory.this), timeout)?,
+                    None => eval_sh(&memory.this, None, timeout)?,
+                };
                 memory.this = stdout;
-                if let Some(code) = code {
+                // like the command substitution in the shells
+                if !keep_newline && memory.this.ends_with('\n') {
+                    memory.this.pop();
+                }
+                if let Some(code) = code
+                    && !ignore_status
+                {
                     return Ok(Status::Quit(code));
                 }
             }
@@ -150,21 +439,412 @@ impl Command {
     }
 }
 
-fn eval_sh(cmd: &str) -> Result<(String, Option<i32>)> {
-    let out = std::process::Command::new("sh")
-        .arg("-c")
+/// Escape the string the same way as `sed`'s `l` command does, wrapping it
+/// so that the lines are at most `width` characters long (including the trailing `\`)
+/// and marking the end of the string with `$`. Width of 0 or 1 means no wrapping.
+/// With `binary`, the raw bytes of the binary input are shown as they were.
+fn escape(s: &str, width: usize, binary: bool) -> String {
+    let mut acc = String::with_capacity(s.len() + 1);
+    let mut col = 0;
+    let mut push = |token: &str| {
+        if width > 1 && col + token.len() > width - 1 {
+            acc.push_str("\\\n");
+            col = 0;
+        }
+        col += token.len();
+        acc.push_str(token);
+    };
+    for c in s.chars() {
+        match c {
+            '\\' => push("\\\\"),
+            '\x07' => push("\\a"),
+            '\x08' => push("\\b"),
+            '\x0C' => push("\\f"),
+            '\n' => push("\\n"),
+            '\r' => push("\\r"),
+            '\t' => push("\\t"),
+            '\x0B' => push("\\v"),
+            c if c.is_ascii_graphic() || c == ' ' => push(c.encode_utf8(&mut [0; 4])),
+            c => {
+                if let Some(b) = crate::lines::raw_byte(c).filter(|_| binary) {
+                    // the raw byte from the binary input
+                    push(&format!("\\{b:03o}"));
+                    continue;
+                }
+                for b in c.encode_utf8(&mut [0; 4]).bytes() {
+                    push(&format!("\\{b:03o}"));
+                }
+            }
+        }
+    }
+    acc.push('$');
+    acc
+}
+
+/// Slice the `[start, end)` byte range of `s`. Indexes out of bounds are clamped to
+/// the length of the string, and the bounds falling in the middle of a multibyte
+/// character are moved inwards, so only the characters fully inside the range are kept.
+fn keep_bytes(s: &str, start: usize, end: usize) -> &str {
+    let mut start = start.min(s.len());
+    while !s.is_char_boundary(start) {
+        start += 1;
+    }
+    let mut end = end.clamp(start, s.len());
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    s.get(start..end).unwrap_or_default()
+}
+
+/// The part of the string before the first newline.
+fn first_line(s: &str) -> &str {
+    s.split_once('\n').map_or(s, |(first, _)| first)
+}
+
+/// Write the `text` as a JSON lines record `{"line": N, "text": "..."}`.
+pub(crate) fn write_json<W: Write>(out: &mut W, line: usize, text: &str) -> Result<()> {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    writeln!(out, r#"{{"line": {line}, "text": "{escaped}"}}"#)?;
+    Ok(())
+}
+
+/// Write to `acc` the `text` with the matches of the `regex` replaced with the `template`,
+/// skipping the first `skip` matches and replacing at most `limit` of them (0 means all).
+/// Returns `false`, leaving `acc` untouched, if there was nothing to replace.
+fn replace_into(
+    regex: &regex::Regex,
+    text: &str,
+    skip: usize,
+    limit: usize,
+    template: &str,
+    acc: &mut String,
+) -> bool {
+    let limit = if limit == 0 { usize::MAX } else { limit };
+    let mut last = 0;
+    let mut replaced = false;
+    let mut push = |acc: &mut String, start: usize, end: usize| {
+        if !replaced {
+            acc.clear();
+            replaced = true;
+        }
+        acc.push_str(&text[last..start]);
+        last = end;
+    };
+    // like in `Regex::replacen`, the captures are only needed when the template refers to them
+    if template.contains('$') {
+        for caps in regex.captures_iter(text).skip(skip).take(limit) {
+            let m = caps.get(0).unwrap();
+            push(acc, m.start(), m.end());
+            caps.expand(template, acc);
+        }
+    } else {
+        for m in regex.find_iter(text).skip(skip).take(limit) {
+            push(acc, m.start(), m.end());
+            acc.push_str(template);
+        }
+    }
+    if !replaced {
+        return false;
+    }
+    acc.push_str(&text[last..]);
+    true
+}
+
+/// Keep the fields at the `indices`, where the fields are separated by the `sep`
+/// character, or by the runs of whitespace if it is not given. The fields are joined
+/// back with the separator (or a single space), missing fields are empty.
+fn fields(s: &str, indices: &[usize], sep: Option<char>) -> String {
+    let parts: Vec<&str> = match sep {
+        Some(c) => s.split(c).collect(),
+        None => s.split_whitespace().collect(),
+    };
+    let selected: Vec<&str> = indices
+        .iter()
+        .map(|&i| parts.get(i).copied().unwrap_or_default())
+        .collect();
+    selected.join(&sep.unwrap_or(' ').to_string())
+}
+
+/// Looks up the value of the variable.
+type Lookup<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+/// The value of the environment variable, if it is set.
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+/// Replace `$N`, `$name`, or `${name}` with the groups from the `captures` of the address regex
+/// (empty if the group did not match), and, if `env` is given, the other `$VAR` or `${VAR}`
+/// with the value it returns for the variable (empty if it is not set). When any of them is
+/// given, `$$` stands for a literal dollar, otherwise the dollars are left as-is.
+/// The escaped `\$` and `\\` always stand for a literal dollar and backslash.
+fn expand<'a>(template: &'a str, env: Option<Lookup>, captures: Option<&Captures>) -> Cow<'a, str> {
+    let active = env.is_some() || captures.is_some();
+    if !(template.contains('\\') || active && template.contains('$')) {
+        return Cow::Borrowed(template);
+    }
+    let mut acc = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(pos) = rest.find(['$', '\\']) {
+        acc.push_str(&rest[..pos]);
+        let c = &rest[pos..pos + 1];
+        rest = &rest[pos + 1..];
+        if c == "\\" {
+            match rest.strip_prefix(['\\', '$']) {
+                Some(tail) => {
+                    acc.push_str(&rest[..1]);
+                    rest = tail;
+                }
+                None => acc.push('\\'),
+            }
+            continue;
+        }
+        if !active {
+            acc.push('$');
+            continue;
+        }
+        if let Some(tail) = rest.strip_prefix('$') {
+            acc.push('$');
+            rest = tail;
+            continue;
+        }
+        let (name, tail) = match rest.strip_prefix('{').and_then(|s| s.split_once('}')) {
+            Some((name, tail)) => (name, tail),
+            None if rest.starts_with(|c: char| c.is_ascii_digit()) => {
+                let end = rest
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(rest.len());
+                rest.split_at(end)
+            }
+            None => {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                rest.split_at(end)
+            }
+        };
+        let group = captures.and_then(|caps| match name.parse::<usize>() {
+            Ok(idx) => (idx < caps.len()).then(|| caps.get(idx)),
+            Err(_) => caps.name(name).map(Some),
+        });
+        let is_var = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_');
+        if let Some(group) = group {
+            acc.push_str(group.map_or("", |m| m.as_str()));
+            rest = tail;
+        } else if let Some(env) = env.filter(|_| is_var) {
+            acc.push_str(&env(name).unwrap_or_default());
+            rest = tail;
+        } else {
+            acc.push('$');
+        }
+    }
+    acc.push_str(rest);
+    Cow::Owned(acc)
+}
+
+/// Collapse the runs of the `c` character to a single one. If `c` is not given,
+/// the runs of whitespace characters are replaced with a single space.
+fn squeeze(s: &str, c: Option<char>) -> String {
+    let mut acc = String::with_capacity(s.len());
+    let mut prev = None;
+    for this in s.chars() {
+        let (this, repeatable) = match c {
+            Some(c) => (this, this == c),
+            None if this.is_whitespace() => (' ', true),
+            None => (this, false),
+        };
+        if repeatable && prev == Some(this) {
+            continue;
+        }
+        acc.push(this);
+        prev = Some(this);
+    }
+    acc
+}
+
+/// Replace the tabs with the spaces up to the next multiple of `width` columns,
+/// like `expand`. Each character takes a single column, the newlines reset the column.
+fn expand_tabs(s: &str, width: usize) -> String {
+    let mut acc = String::with_capacity(s.len());
+    let mut column = 0;
+    for c in s.chars() {
+        match c {
+            '\t' => {
+                let spaces = width - column % width;
+                acc.extend(std::iter::repeat_n(' ', spaces));
+                column += spaces;
+            }
+            '\n' => {
+                acc.push(c);
+                column = 0;
+            }
+            _ => {
+                acc.push(c);
+                column += 1;
+            }
+        }
+    }
+    acc
+}
+
+/// Split the shell definition like `bash -c` into the program and its arguments.
+/// When only the program is given, `-c` is used as the argument. If the definition
+/// is empty, `sh -c` is used.
+fn shell(var: Option<&str>) -> (&str, Vec<&str>) {
+    let mut parts = var.unwrap_or_default().split_whitespace();
+    match parts.next() {
+        Some(program) => {
+            let args: Vec<&str> = parts.collect();
+            if args.is_empty() {
+                (program, vec!["-c"])
+            } else {
+                (program, args)
+            }
+        }
+        None => ("sh", vec!["-c"]),
+    }
+}
+
+/// Parse the `SE_EVAL_TIMEOUT` value given in seconds, an empty value or 0 means no timeout.
+fn eval_timeout(var: Option<&str>) -> Result<Option<Duration>> {
+    let Some(var) = var.map(str::trim).filter(|s| !s.is_empty()) else {
+        return Ok(None);
+    };
+    let secs: f64 = var
+        .parse()
+        .map_err(|_| anyhow!("invalid SE_EVAL_TIMEOUT value: '{}'", var))?;
+    let timeout = Duration::try_from_secs_f64(secs)
+        .map_err(|_| anyhow!("invalid SE_EVAL_TIMEOUT value: '{}'", var))?;
+    if timeout.is_zero() {
+        return Ok(None);
+    }
+    Ok(Some(timeout))
+}
+
+/// Read the `pipe` in a separate thread to the shared buffer.
+fn collect<P: Read + Send + 'static>(
+    mut pipe: P,
+) -> (Arc<Mutex<Vec<u8>>>, JoinHandle<std::io::Result<()>>) {
+    let buffer = Arc::new(Mutex::new(Vec::new()));
+    let shared = buffer.clone();
+    let handle = std::thread::spawn(move || {
+        let mut chunk = [0; 4096];
+        loop {
+            let n = pipe.read(&mut chunk)?;
+            if n == 0 {
+                return Ok(());
+            }
+            shared.lock().unwrap().extend_from_slice(&chunk[..n]);
+        }
+    });
+    (buffer, handle)
+}
+
+/// Wait for the child to finish, if it takes longer than `timeout` kill it and return `None`.
+fn wait(child: &mut Child, timeout: Option<Duration>) -> Result<Option<ExitStatus>> {
+    let Some(timeout) = timeout else {
+        return Ok(Some(child.wait()?));
+    };
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Run `cmd` using the shell and capture its stdout. If `input` is given,
+/// it is passed to the stdin of the command followed by a newline.
+/// If the command does not finish within the `timeout`, it is killed and an error is returned.
+fn eval_sh(
+    cmd: &str,
+    input: Option<&str>,
+    timeout: Option<Duration>,
+) -> Result<(String, Option<i32>)> {
+    let var = std::env::var("SE_SHELL").ok();
+    let (shell, args) = shell(var.as_deref());
+    let mut child = std::process::Command::new(shell)
+        .args(args)
         .arg(cmd)
-        .output()?;
-    if !out.stderr.is_empty() {
-        std::io::stderr().write_all(&out.stderr)?;
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // write from a separate thread so that the command is not blocked on a full stdout pipe
+    let writer = match (input, child.stdin.take()) {
+        (Some(input), Some(mut stdin)) => {
+            let input = format!("{input}\n");
+            Some(std::thread::spawn(move || {
+                stdin.write_all(input.as_bytes())
+            }))
+        }
+        _ => None,
+    };
+    let (stdout, stdout_reader) = collect(child.stdout.take().unwrap());
+    let (stderr, stderr_reader) = collect(child.stderr.take().unwrap());
+
+    let Some(status) = wait(&mut child, timeout)? else {
+        // the pipes may be still held by the processes started by the command,
+        // so don't wait for the readers, just forward what was collected so far
+        std::io::stderr().write_all(&stderr.lock().unwrap())?;
+        bail!("command '{}' timed out after {:?}", cmd, timeout.unwrap());
+    };
+
+    if let Some(writer) = writer {
+        // the command is not obliged to read its input, so a broken pipe is fine
+        let _ = writer.join();
     }
-    let stdout = std::str::from_utf8(&out.stdout)?.to_string();
-    let code = match out.status.code() {
+    stdout_reader.join().unwrap()?;
+    stderr_reader.join().unwrap()?;
+
+    let stderr = stderr.lock().unwrap();
+    if !stderr.is_empty() {
+        std::io::stderr().write_all(&stderr)?;
+    }
+    let stdout = std::str::from_utf8(&stdout.lock().unwrap())?.to_string();
+    Ok((stdout, exit_code(status)))
+}
+
+/// Exit code of the command, or `None` if it succeeded. Like in the shells,
+/// the code of a command killed by a signal is 128 plus the number of the signal.
+fn exit_code(status: ExitStatus) -> Option<i32> {
+    match status.code() {
         Some(0) => None,
         Some(code) => Some(code),
-        None => Some(0),
-    };
-    Ok((stdout, code))
+        None => Some(128 + signal(status)),
+    }
+}
+
+#[cfg(unix)]
+fn signal(status: ExitStatus) -> i32 {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal().unwrap_or_default()
+}
+
+#[cfg(not(unix))]
+fn signal(_: ExitStatus) -> i32 {
+    0
 }
 
 impl std::fmt::Display for Command {
@@ -172,26 +852,106 @@ impl std::fmt::Display for Command {
         use Command::*;
         match self {
             Println => write!(f, "p"),
-            Print => write!(f, "P"),
-            Escapeln => write!(f, "l"),
-            LineNumber => write!(f, "="),
+            Print(true) => write!(f, "P"),
+            Print(false) => write!(f, "P-"),
+            Escapeln(None) => write!(f, "l"),
+            Escapeln(Some(w)) => write!(f, "l {w}"),
+            LineNumber(true) => write!(f, "="),
+            LineNumber(false) => write!(f, "=-"),
+            PrintFilename => write!(f, "F"),
             Insert(s) => write!(f, "'{s}'"),
-            Substitute(r, t, l) => write!(f, "s/{r}/{t}/{l}"),
-            Keep(s, None) => write!(f, "k {}-", s + 1),
-            Keep(s, Some(t)) => write!(f, "k {}-{}", s + 1, s + t),
-            Hold => write!(f, "h"),
-            Get => write!(f, "g"),
+            Substitute {
+                regex,
+                replacement,
+                limit,
+                global,
+            } => {
+                write!(f, "s/{regex}/{replacement}/{limit}")?;
+                if *global {
+                    write!(f, "g")?;
+                }
+                Ok(())
+            }
+            Keep(s, e, bytes) => {
+                write!(f, "{}", if *bytes { "kb" } else { "k" })?;
+                match s {
+                    Position::Start(s) => write!(f, " {}-", s + 1)?,
+                    Position::End(s) => write!(f, " -{s}-")?,
+                }
+                match e {
+                    Some(Position::Start(e)) => write!(f, "{e}"),
+                    Some(Position::End(e)) => write!(f, "-{e}"),
+                    None => Ok(()),
+                }
+            }
+            Hold(None) => write!(f, "h"),
+            Hold(Some(name)) => write!(f, "h:{name}"),
+            HoldAppend => write!(f, "H"),
+            Get(None) => write!(f, "g"),
+            Get(Some(name)) => write!(f, "g:{name}"),
             GetLine => write!(f, "&"),
-            Exchange => write!(f, "x"),
+            Exchange(None) => write!(f, "x"),
+            Exchange(Some(name)) => write!(f, "x:{name}"),
             Joinln => write!(f, "j"),
             Join => write!(f, "J"),
             Readln(n) => write!(f, "r {n}"),
             ReadReplace => write!(f, "R"),
+            ReadFile(path) => write!(f, "r'{}'", path.display()),
+            WriteFile(path) => write!(f, "w'{}'", path.display()),
+            Call(name) => write!(f, "@{name}"),
+            Field(indices, sep) => {
+                let indices: Vec<_> = indices.iter().map(|i| (i + 1).to_string()).collect();
+                write!(f, "c{}", indices.join(","))?;
+                // the parser takes the character following `-d` as-is
+                match sep {
+                    Some(c) => write!(f, " -d{c}"),
+                    None => Ok(()),
+                }
+            }
+            Squeeze(None) => write!(f, "S"),
+            Squeeze(Some(c)) => write!(f, "S/{}/", c.escape_default()),
+            Expand(width) => write!(f, "E {width}"),
+            NumberPrefix(width, sep) => write!(f, "n {width} -s'{}'", sep.escape_default()),
+            Trim => write!(f, "~"),
+            TrimStart => write!(f, "~^"),
+            TrimEnd => write!(f, "~$"),
+            Reverse => write!(f, "v"),
+            Upper => write!(f, "U"),
+            Lower => write!(f, "L"),
             Reset => write!(f, "z"),
             Delete => write!(f, "d"),
+            SqueezeBlank => write!(f, "B"),
+            Uniq(indices, _) if indices.is_empty() => write!(f, "u"),
+            Uniq(indices, sep) => {
+                let indices: Vec<_> = indices.iter().map(|i| (i + 1).to_string()).collect();
+                write!(f, "u{}", indices.join(","))?;
+                match sep {
+                    Some(c) => write!(f, " -d{c}"),
+                    None => Ok(()),
+                }
+            }
             Break => write!(f, "."),
+            BranchIfSub => write!(f, "t"),
+            BranchIfNoSub => write!(f, "T"),
             Quit(c) => write!(f, "q {c}"),
-            Eval => write!(f, "e"),
+            QuitSilent(c) => write!(f, "Q {c}"),
+            Eval {
+                command,
+                ignore_status,
+                keep_newline,
+            } => {
+                write!(f, "e")?;
+                if *ignore_status {
+                    write!(f, "!")?;
+                }
+                if *keep_newline {
+                    write!(f, "+")?;
+                }
+                if let Some(cmd) = command {
+                    write!(f, "'{cmd}'")?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -199,35 +959,52 @@ impl std::fmt::Display for Command {
 #[cfg(test)]
 mod tests {
     use super::Command;
-    use crate::{Line, lines::MockReader, program::Memory};
+    use crate::{
+        Action, Line, Program, Regex, Status, address::Address, lines::MockReader, program::Memory,
+    };
+    use std::str::FromStr;
+    use test_case::test_case;
 
     #[test]
     fn readln() {
         let example = [1, 2, 3, 4, 5];
-        let mut reader = example.iter().map(|n| Ok(Line(*n, n.to_string())));
+        let mut reader = example.iter().map(|n| Ok(Line(*n, n.to_string(), None)));
         let mut memory = Memory::default();
-        memory.read(Line(0, "start".to_string()));
+        memory.read(Line(0, "start".to_string(), None));
 
         Command::Readln(1)
-            .run(&mut memory, &mut reader, &mut std::io::stdout().lock())
+            .run(
+                &mut memory,
+                None,
+                &mut reader,
+                &mut std::io::stdout().lock(),
+            )
             .unwrap();
         assert_eq!(memory.this, "start\n1");
+        assert_eq!(memory.line.0, 1);
 
         Command::Readln(4)
-            .run(&mut memory, &mut reader, &mut std::io::stdout().lock())
+            .run(
+                &mut memory,
+                None,
+                &mut reader,
+                &mut std::io::stdout().lock(),
+            )
             .unwrap();
         assert_eq!(memory.this, "start\n1\n2\n3\n4\n5");
+        assert_eq!(memory.line.0, 5);
     }
 
     #[test]
     fn join() {
         let mut memory = Memory::default();
-        memory.read(Line(0, "one".to_string()));
+        memory.read(Line(0, "one".to_string(), None));
         memory.hold = "two".to_string();
 
         Command::Join
             .run(
                 &mut memory,
+                None,
                 &mut MockReader {},
                 &mut std::io::stdout().lock(),
             )
@@ -238,12 +1015,13 @@ mod tests {
     #[test]
     fn joinln() {
         let mut memory = Memory::default();
-        memory.read(Line(0, "one".to_string()));
+        memory.read(Line(0, "one".to_string(), None));
         memory.hold = "two".to_string();
 
         Command::Joinln
             .run(
                 &mut memory,
+                None,
                 &mut MockReader {},
                 &mut std::io::stdout().lock(),
             )
@@ -251,15 +1029,34 @@ mod tests {
         assert_eq!(memory.this, "one\ntwo");
     }
 
+    #[test]
+    fn hold_append() {
+        let mut memory = Memory::default();
+        memory.read(Line(0, "two".to_string(), None));
+        memory.hold = "one".to_string();
+
+        Command::HoldAppend
+            .run(
+                &mut memory,
+                None,
+                &mut MockReader {},
+                &mut std::io::stdout().lock(),
+            )
+            .unwrap();
+        assert_eq!(memory.this, "two");
+        assert_eq!(memory.hold, "one\ntwo");
+    }
+
     #[test]
     fn exchange() {
         let mut memory = Memory::default();
-        memory.read(Line(0, "one".to_string()));
+        memory.read(Line(0, "one".to_string(), None));
         memory.hold = "two".to_string();
 
-        Command::Exchange
+        Command::Exchange(None)
             .run(
                 &mut memory,
+                None,
                 &mut MockReader {},
                 &mut std::io::stdout().lock(),
             )
@@ -267,4 +1064,382 @@ mod tests {
         assert_eq!(memory.this, "two");
         assert_eq!(memory.hold, "one");
     }
+
+    #[test_case(None, "a    b\t\tc", "a b c"; "whitespace")]
+    #[test_case(None, "  a \t b  ", " a b "; "whitespace at the ends")]
+    #[test_case(Some('-'), "a---b--c-d", "a-b-c-d"; "dashes")]
+    #[test_case(Some('-'), "a  b--c", "a  b-c"; "dashes but not whitespace")]
+    fn squeeze(c: Option<char>, input: &str, expected: &str) {
+        let mut memory = Memory::default();
+        memory.read(Line(0, input.to_string(), None));
+
+        Command::Squeeze(c)
+            .run(
+                &mut memory,
+                None,
+                &mut MockReader {},
+                &mut std::io::stdout().lock(),
+            )
+            .unwrap();
+        assert_eq!(memory.this, expected);
+    }
+
+    #[test_case(8, "\ta", "        a"; "tab at the start")]
+    #[test_case(8, "abc\td", "abc     d"; "tab after three characters")]
+    #[test_case(8, "abcdefgh\ti", "abcdefgh        i"; "tab at the tab stop")]
+    #[test_case(8, "a\t\tb", "a               b"; "consecutive tabs")]
+    #[test_case(4, "ab\tc\td", "ab  c   d"; "custom width")]
+    #[test_case(4, "żół\tx", "żół x"; "multibyte")]
+    #[test_case(4, "ab\tc\nd\te", "ab  c\nd   e"; "newline resets the column")]
+    #[test_case(8, "no tabs", "no tabs"; "no tabs")]
+    fn expand_tabs(width: usize, input: &str, expected: &str) {
+        let mut memory = Memory::default();
+        memory.read(Line(0, input.to_string(), None));
+
+        Command::Expand(width)
+            .run(
+                &mut memory,
+                None,
+                &mut MockReader {},
+                &mut std::io::stdout().lock(),
+            )
+            .unwrap();
+        assert_eq!(memory.this, expected);
+    }
+
+    #[test_case(6, "\t", 42, "    42\tabc"; "default")]
+    #[test_case(3, ": ", 7, "  7: abc"; "custom separator")]
+    #[test_case(0, "", 12, "12abc"; "no padding")]
+    #[test_case(2, " ", 1234, "1234 abc"; "number wider than width")]
+    fn number_prefix(width: usize, sep: &str, line: usize, expected: &str) {
+        let mut memory = Memory::default();
+        memory.read(Line(line, "abc".to_string(), None));
+
+        Command::NumberPrefix(width, sep.to_string())
+            .run(
+                &mut memory,
+                None,
+                &mut MockReader {},
+                &mut std::io::stdout().lock(),
+            )
+            .unwrap();
+        assert_eq!(memory.this, expected);
+    }
+
+    #[test_case("abcdef", 1, 4, "bcd"; "ascii")]
+    #[test_case("abcdef", 4, 20, "ef"; "out of bounds")]
+    #[test_case("abcdef", 10, 20, ""; "start out of bounds")]
+    #[test_case("zażółć", 2, 6, "żó"; "whole characters")]
+    #[test_case("zażółć", 3, 6, "ó"; "start inside a character")]
+    #[test_case("zażółć", 2, 5, "ż"; "end inside a character")]
+    #[test_case("zażółć", 3, 4, ""; "inside a single character")]
+    fn keep_bytes(input: &str, start: usize, end: usize, expected: &str) {
+        assert_eq!(super::keep_bytes(input, start, end), expected);
+    }
+
+    #[test_case("a b c", vec![1], None, "b"; "single field")]
+    #[test_case("  a \t b   c ", vec![0, 2], None, "a c"; "runs of whitespace")]
+    #[test_case("a b", vec![0, 4], None, "a "; "whitespace out of range")]
+    #[test_case("a:b:c:d", vec![1, 3], Some(':'), "b:d"; "separator")]
+    #[test_case("a::c", vec![1, 2], Some(':'), ":c"; "consecutive separators")]
+    #[test_case("a:b", vec![2, 0], Some(':'), ":a"; "separator out of range")]
+    #[test_case("abc", vec![0], Some(':'), "abc"; "no separator")]
+    fn fields(input: &str, indices: Vec<usize>, sep: Option<char>, expected: &str) {
+        assert_eq!(super::fields(input, &indices, sep), expected);
+    }
+
+    #[test_case(Command::Field(vec![0, 2], Some(':')); "fields")]
+    #[test_case(Command::Field(vec![1], Some('\t')); "fields split on tab")]
+    #[test_case(Command::Field(vec![1], Some('\\')); "fields split on backslash")]
+    #[test_case(Command::Uniq(vec![0], Some('\t')); "uniq split on tab")]
+    fn display_parses_back(cmd: Command) {
+        let prog = Program::from_str(&cmd.to_string()).unwrap();
+        let expected = Program::from(vec![
+            Action::Condition(Address::Always, 1),
+            Action::Command(cmd),
+        ]);
+        assert_eq!(prog, expected);
+    }
+
+    #[test_case("no variables", false, "no variables"; "no dollars")]
+    #[test_case("$$HOME $$", false, "$$HOME $$"; "dollars not expanded")]
+    #[test_case("$$HOME $$", true, "$HOME $"; "literal dollars")]
+    #[test_case("$SE_TEST_SET", false, "$SE_TEST_SET"; "not expanded")]
+    #[test_case("a $SE_TEST_SET b", true, "a value b"; "set")]
+    #[test_case("a ${SE_TEST_SET}b", true, "a valueb"; "braces")]
+    #[test_case("a $SE_TEST_UNSET b", true, "a  b"; "unset")]
+    #[test_case("$$SE_TEST_SET", true, "$SE_TEST_SET"; "escaped")]
+    #[test_case("$1 $ ${ $-", true, "$1 $ ${ $-"; "not variables")]
+    #[test_case(r"\$x $$x \\$x", false, r"$x $$x \$x"; "escaped dollar not expanded")]
+    #[test_case(r"\$SE_TEST_SET \\$SE_TEST_SET", true, r"$SE_TEST_SET \value"; "escaped dollar")]
+    fn expand(template: &str, env: bool, expected: &str) {
+        let lookup = |name: &str| (name == "SE_TEST_SET").then(|| "value".to_string());
+        let env = env.then_some(&lookup as super::Lookup);
+        assert_eq!(super::expand(template, env, None), expected);
+    }
+
+    #[test_case("id=$1", "id=42"; "group")]
+    #[test_case("$0!", "42y!"; "whole match")]
+    #[test_case("[$1a]", "[42a]"; "digits end the group")]
+    #[test_case("${1}a", "42a"; "braces")]
+    #[test_case("$name|$other|${name}", "y|$other|y"; "named group")]
+    #[test_case("[$2]", "[]"; "group not matched")]
+    #[test_case("$9 $$1", "$9 $1"; "not groups")]
+    #[test_case(r"\$1 \\$1", r"$1 \42"; "escaped")]
+    fn expand_captures(template: &str, expected: &str) {
+        let regex = regex::Regex::new(r"(\d+)(z)?(?<name>y)").unwrap();
+        let captures = regex.captures("ax42yb").unwrap();
+        assert_eq!(super::expand(template, None, Some(&captures)), expected);
+    }
+
+    #[test_case(Command::Trim, " \t a b\t \n", "a b"; "trim")]
+    #[test_case(Command::TrimStart, " \t a b\t \n", "a b\t \n"; "trim start")]
+    #[test_case(Command::TrimEnd, " \t a b\t \n", " \t a b"; "trim end")]
+    #[test_case(Command::Trim, "\u{3000}a\u{a0}", "a"; "unicode whitespace")]
+    #[test_case(Command::Trim, "a b", "a b"; "nothing to trim")]
+    fn trim(cmd: Command, input: &str, expected: &str) {
+        let mut memory = Memory::default();
+        memory.read(Line(0, input.to_string(), None));
+        cmd.run(
+            &mut memory,
+            None,
+            &mut MockReader {},
+            &mut std::io::stdout().lock(),
+        )
+        .unwrap();
+        assert_eq!(memory.this, expected);
+        assert_eq!(
+            Program::from_str(&cmd.to_string()).unwrap().to_string(),
+            format!("//\n  {cmd}\n")
+        );
+    }
+
+    #[test_case(Command::Print(true), "a\nb", "a\n"; "first line")]
+    #[test_case(Command::Print(true), "a", "a\n"; "single line")]
+    #[test_case(Command::Print(true), "\nb", "\n"; "empty first line")]
+    #[test_case(Command::Print(false), "a\nb", "a\nb"; "without newline")]
+    #[test_case(Command::Println, "a\nb", "a\nb\n"; "all lines")]
+    fn print(cmd: Command, input: &str, expected: &str) {
+        let mut memory = Memory::default();
+        memory.read(Line(0, input.to_string(), None));
+        let mut out = Vec::new();
+        cmd.run(&mut memory, None, &mut MockReader {}, &mut out)
+            .unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+        assert_eq!(
+            Program::from_str(&cmd.to_string()).unwrap().to_string(),
+            format!("//\n  {cmd}\n")
+        );
+    }
+
+    #[test_case("a", "X", 0, 0, Some("XbXbX"); "all")]
+    #[test_case("a", "X", 0, 2, Some("XbXba"); "limit")]
+    #[test_case("a", "X", 1, 0, Some("abXbX"); "skip")]
+    #[test_case("a", "X", 1, 1, Some("abXba"); "skip and limit")]
+    #[test_case("(a)b", "[$1]", 0, 0, Some("[a][a]a"); "captures")]
+    #[test_case("a", "X", 3, 0, None; "skipped all")]
+    #[test_case("x", "X", 0, 0, None; "no match")]
+    fn replace_into(
+        regex: &str,
+        template: &str,
+        skip: usize,
+        limit: usize,
+        expected: Option<&str>,
+    ) {
+        let regex = regex::Regex::new(regex).unwrap();
+        let mut acc = "old".to_string();
+        let replaced = super::replace_into(&regex, "ababa", skip, limit, template, &mut acc);
+        assert_eq!(replaced, expected.is_some());
+        assert_eq!(acc, expected.unwrap_or("old"));
+    }
+
+    #[test_case(0, false; "all")]
+    #[test_case(2, false; "limit")]
+    #[test_case(2, true; "from the nth match")]
+    fn substitute_without_match(limit: usize, from: bool) {
+        let mut memory = Memory::default();
+        memory.read(Line(0, "hello, world!".to_string(), None));
+        let ptr = memory.this.as_ptr();
+
+        Command::Substitute {
+            regex: Regex::from_str("x").unwrap(),
+            replacement: "y".to_string(),
+            limit,
+            global: from,
+        }
+        .run(&mut memory, None, &mut MockReader {}, &mut std::io::sink())
+        .unwrap();
+        assert_eq!(memory.this, "hello, world!");
+        // the buffer was not reallocated
+        assert_eq!(memory.this.as_ptr(), ptr);
+        assert!(!memory.substituted);
+    }
+
+    #[test_case("hello, world!", "!dlrow ,olleh"; "ascii")]
+    #[test_case("zażółć", "ćłóżaz"; "multibyte")]
+    #[test_case("e\u{301}a", "a\u{301}e"; "combining character")]
+    #[test_case("", ""; "empty")]
+    fn reverse(input: &str, expected: &str) {
+        let mut memory = Memory::default();
+        memory.read(Line(0, input.to_string(), None));
+        Command::Reverse
+            .run(
+                &mut memory,
+                None,
+                &mut MockReader {},
+                &mut std::io::stdout().lock(),
+            )
+            .unwrap();
+        assert_eq!(memory.this, expected);
+    }
+
+    #[test_case(Command::Upper, "Hello, World!", "HELLO, WORLD!"; "upper")]
+    #[test_case(Command::Lower, "Hello, World!", "hello, world!"; "lower")]
+    #[test_case(Command::Upper, "straße", "STRASSE"; "multiple chars uppercase")]
+    #[test_case(Command::Upper, "ﬁx ǆ", "FIX Ǆ"; "ligatures")]
+    #[test_case(Command::Lower, "ZAŻÓŁĆ", "zażółć"; "multibyte")]
+    #[test_case(Command::Lower, "ΟΔΟΣ", "οδος"; "final sigma")]
+    fn change_case(cmd: Command, input: &str, expected: &str) {
+        let mut memory = Memory::default();
+        memory.read(Line(0, input.to_string(), None));
+        cmd.run(
+            &mut memory,
+            None,
+            &mut MockReader {},
+            &mut std::io::stdout().lock(),
+        )
+        .unwrap();
+        assert_eq!(memory.this, expected);
+    }
+
+    #[test_case("abc", r#"{"line": 7, "text": "abc"}"#; "plain")]
+    #[test_case("say \"hi\"\tnow", r#"{"line": 7, "text": "say \"hi\"\tnow"}"#; "quotes and tab")]
+    #[test_case("a\\b\r\n", r#"{"line": 7, "text": "a\\b\r\n"}"#; "backslash and newlines")]
+    #[test_case("\x01\x7f ż", r#"{"line": 7, "text": "\u0001\u007f ż"}"#; "control characters")]
+    fn write_json(input: &str, expected: &str) {
+        let mut out = Vec::new();
+        super::write_json(&mut out, 7, input).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), format!("{expected}\n"));
+    }
+
+    #[test_case("", 70, "$"; "empty")]
+    #[test_case("a\u{e9}\t\\x\x01\"'", 70, "a\\303\\251\\t\\\\x\\001\"'$"; "special characters")]
+    #[test_case("a\tb\t\tc", 70, "a\\tb\\t\\tc$"; "tabs")]
+    #[test_case(r"C:\dir\\x", 70, r"C:\\dir\\\\x$"; "backslashes")]
+    #[test_case(r#"it's "quoted" `x`"#, 70, r#"it's "quoted" `x`$"#; "quotes")]
+    #[test_case("a$b\nc", 70, "a$b\\nc$"; "dollar and newline")]
+    #[test_case("abcdefghijklmnop", 10, "abcdefghi\\\njklmnop$"; "wrapped")]
+    #[test_case("abcdefgh\t", 10, "abcdefgh\\\n\\t$"; "escape is not split")]
+    #[test_case("abcdefghijklmnop", 0, "abcdefghijklmnop$"; "no wrapping")]
+    fn escape(input: &str, width: usize, expected: &str) {
+        assert_eq!(super::escape(input, width, false), expected);
+    }
+
+    #[test_case("a\u{10FFFF}", false, "a\\364\\217\\277\\277$"; "code point")]
+    #[test_case("a\u{10FFFF}", true, "a\\377$"; "raw byte")]
+    fn escape_binary(input: &str, binary: bool, expected: &str) {
+        assert_eq!(super::escape(input, 0, binary), expected);
+    }
+
+    #[test_case(false, "HELLO, WORLD!"; "without newline")]
+    #[test_case(true, "HELLO, WORLD!\n"; "keep newline")]
+    fn eval_with_input(keep_newline: bool, expected: &str) {
+        let mut memory = Memory::default();
+        memory.read(Line(0, "hello, world!".to_string(), None));
+
+        Command::Eval {
+            command: Some("tr a-z A-Z".to_string()),
+            ignore_status: false,
+            keep_newline,
+        }
+        .run(
+            &mut memory,
+            None,
+            &mut MockReader {},
+            &mut std::io::stdout().lock(),
+        )
+        .unwrap();
+        assert_eq!(memory.this, expected);
+    }
+
+    #[test_case("echo hi", "hi"; "single newline")]
+    #[test_case("printf hi", "hi"; "no newline")]
+    #[test_case("printf 'hi\\n\\n'", "hi\n"; "only the last newline")]
+    fn eval_strips_newline(cmd: &str, expected: &str) {
+        let mut memory = Memory::default();
+        memory.read(Line(0, cmd.to_string(), None));
+
+        Command::Eval {
+            command: None,
+            ignore_status: false,
+            keep_newline: false,
+        }
+        .run(&mut memory, None, &mut MockReader {}, &mut std::io::sink())
+        .unwrap();
+        assert_eq!(memory.this, expected);
+    }
+
+    #[test_case(false, Status::Quit(1); "exit code")]
+    #[test_case(true, Status::Normal; "ignored exit code")]
+    fn eval_exit_code(ignore_status: bool, expected: Status) {
+        let mut memory = Memory::default();
+        memory.read(Line(0, "hello".to_string(), None));
+
+        let status = Command::Eval {
+            command: Some("cat; exit 1".to_string()),
+            ignore_status,
+            keep_newline: false,
+        }
+        .run(&mut memory, None, &mut MockReader {}, &mut std::io::sink())
+        .unwrap();
+        assert_eq!(status, expected);
+        assert_eq!(memory.this, "hello");
+    }
+
+    #[cfg(unix)]
+    #[test_case("kill -9 $$", Some(137); "killed")]
+    #[test_case("kill -15 $$", Some(143); "terminated")]
+    #[test_case("exit 3", Some(3); "exit code")]
+    #[test_case("true", None; "success")]
+    fn eval_exit_status(cmd: &str, expected: Option<i32>) {
+        let (_, code) = super::eval_sh(cmd, None, None).unwrap();
+        assert_eq!(code, expected);
+    }
+
+    #[test_case(None, "sh", vec!["-c"]; "not set")]
+    #[test_case(Some(""), "sh", vec!["-c"]; "empty")]
+    #[test_case(Some("  "), "sh", vec!["-c"]; "blank")]
+    #[test_case(Some("bash"), "bash", vec!["-c"]; "program only")]
+    #[test_case(Some("cmd /C"), "cmd", vec!["/C"]; "program and argument")]
+    #[test_case(Some("bash -e -c"), "bash", vec!["-e", "-c"]; "many arguments")]
+    fn shell(var: Option<&str>, program: &str, args: Vec<&str>) {
+        assert_eq!(super::shell(var), (program, args));
+    }
+
+    #[test]
+    fn eval_timeout() {
+        let timeout = Some(std::time::Duration::from_millis(100));
+        let start = std::time::Instant::now();
+        assert!(super::eval_sh("sleep 5", None, timeout).is_err());
+        assert!(start.elapsed().as_secs() < 5);
+
+        let (stdout, code) = super::eval_sh("printf ok", None, timeout).unwrap();
+        assert_eq!(stdout, "ok");
+        assert_eq!(code, None);
+    }
+
+    #[test_case(None, None; "not set")]
+    #[test_case(Some(""), None; "empty")]
+    #[test_case(Some("0"), None; "zero")]
+    #[test_case(Some("2"), Some(std::time::Duration::from_secs(2)); "seconds")]
+    #[test_case(Some("0.5"), Some(std::time::Duration::from_millis(500)); "fraction")]
+    fn parse_eval_timeout(var: Option<&str>, expected: Option<std::time::Duration>) {
+        assert_eq!(super::eval_timeout(var).unwrap(), expected);
+    }
+
+    #[test_case("abc"; "not a number")]
+    #[test_case("-1"; "negative")]
+    fn invalid_eval_timeout(var: &str) {
+        assert!(super::eval_timeout(Some(var)).is_err());
+    }
 }
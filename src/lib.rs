@@ -5,16 +5,35 @@ mod parser;
 mod program;
 
 pub use {
-    command::Status,
-    lines::{FilesReader, Line, StdinReader},
-    program::Program,
+    address::{Address, Between, Comparison},
+    command::{Command, Position, Status},
+    lines::{
+        BOM, BinaryWriter, FilesReader, FollowReader, Line, NewlineWriter, RecordsReader,
+        StdinReader,
+    },
+    parser::{Error as ParseError, Script, unescape},
+    program::{Outcome, Program, Transform},
 };
 
+/// Compiled regular expression, displayed as its pattern.
 #[derive(Debug, Clone)]
-pub(crate) struct Regex(regex::Regex);
+pub struct Regex(regex::Regex);
 
-#[derive(Debug, PartialEq)]
-pub(crate) enum Action {
+/// Default flags of all the regular expressions in the program,
+/// the inline flags like `(?-s)` in the patterns override them.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RegexOptions {
+    /// `.` matches also the newlines, like the `(?s)` flag
+    pub dotall: bool,
+    /// `^` and `$` match at the start and end of each line, like the `(?m)` flag
+    pub multiline: bool,
+}
+
+/// Compiled instruction of the [`Program`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// When the address does not match the line, the given number of the following
+    /// actions (the commands of the instruction) are skipped.
     Condition(address::Address, usize),
     Command(command::Command),
 }
@@ -38,6 +57,22 @@ impl std::str::FromStr for Regex {
     }
 }
 
+impl Regex {
+    /// The pattern of the regular expression.
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+
+    /// Compile the same pattern again using the `options`.
+    pub(crate) fn with_options(&self, options: RegexOptions) -> anyhow::Result<Regex> {
+        let regex = regex::RegexBuilder::new(self.0.as_str())
+            .dot_matches_new_line(options.dotall)
+            .multi_line(options.multiline)
+            .build()?;
+        Ok(Regex(regex))
+    }
+}
+
 impl PartialEq for Regex {
     fn eq(&self, other: &Self) -> bool {
         self.0.as_str() == other.0.as_str()
@@ -1,23 +1,169 @@
-use anyhow::Result;
+use anyhow::{Result, anyhow, bail};
+use flate2::read::MultiGzDecoder;
 use std::{
+    cell::Cell,
     fs::File,
-    io::{BufRead, BufReader, Lines},
-    path::PathBuf,
+    io::{BufRead, BufReader, Cursor, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::Arc,
+    time::Duration,
 };
 
-#[derive(Debug, PartialEq, Default)]
-pub struct Line(pub usize, pub String);
+/// Line number, content, and the file it was read from (`None` for stdin).
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Line(pub usize, pub String, pub Option<Arc<PathBuf>>);
+
+impl Line {
+    /// Line read from stdin.
+    ///
+    /// ```
+    /// use se::Line;
+    ///
+    /// let line = Line::new(3, "hello");
+    /// assert_eq!(line.number(), 3);
+    /// assert_eq!(line.text(), "hello");
+    /// assert_eq!(line.path(), None);
+    /// ```
+    pub fn new(number: usize, text: impl Into<String>) -> Line {
+        Line(number, text.into(), None)
+    }
+
+    /// Line number, counted from 1 and continued across the files.
+    pub fn number(&self) -> usize {
+        self.0
+    }
+
+    /// Content of the line, without the trailing newline.
+    pub fn text(&self) -> &str {
+        &self.1
+    }
+
+    /// File the line was read from, `None` for stdin.
+    pub fn path(&self) -> Option<&Path> {
+        self.2.as_deref().map(PathBuf::as_path)
+    }
+
+    /// Take the content of the line.
+    pub fn into_text(self) -> String {
+        self.1
+    }
+}
+
+/// Byte order mark, it is removed from the start of the inputs.
+pub const BOM: char = '\u{FEFF}';
+
+fn strip_bom(mut line: String) -> String {
+    if line.starts_with(BOM) {
+        line.drain(..BOM.len_utf8());
+    }
+    line
+}
+
+/// In the binary mode, the bytes that are not valid UTF-8 are mapped to the characters
+/// starting at this code point (from the private use area), so they can be written back.
+/// The characters from this range that are valid in the input are mapped to their bytes
+/// as well, but the ones coming from the script are written as the raw bytes, and `l`
+/// shows them as the raw bytes also outside of the binary mode.
+const RAW_BYTES: u32 = 0x10FF00;
+
+/// Decode the bytes as UTF-8, mapping the invalid bytes to the [`RAW_BYTES`] characters.
+fn decode_binary(bytes: &[u8]) -> String {
+    let push_raw = |acc: &mut String, bytes: &[u8]| {
+        for &b in bytes {
+            acc.push(char::from_u32(RAW_BYTES + b as u32).unwrap());
+        }
+    };
+    let mut acc = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        let valid = chunk.valid();
+        // all the characters from the range start with the 0xF4 byte
+        if valid.as_bytes().contains(&0xF4) {
+            for c in valid.chars() {
+                match raw_byte(c) {
+                    Some(_) => push_raw(&mut acc, c.encode_utf8(&mut [0; 4]).as_bytes()),
+                    None => acc.push(c),
+                }
+            }
+        } else {
+            acc.push_str(valid);
+        }
+        push_raw(&mut acc, chunk.invalid());
+    }
+    acc
+}
+
+/// The byte represented by the character in the binary mode.
+pub(crate) fn raw_byte(c: char) -> Option<u8> {
+    (c as u32)
+        .checked_sub(RAW_BYTES)
+        .and_then(|b| u8::try_from(b).ok())
+}
+
+/// Read the line without the line terminator, and whether the terminator was present.
+fn read_line<R: BufRead + ?Sized>(
+    reader: &mut R,
+    binary: bool,
+    separator: u8,
+) -> Option<Result<(String, bool)>> {
+    let mut buf = Vec::new();
+    match reader.read_until(separator, &mut buf) {
+        Ok(0) => None,
+        Ok(_) => Some(decode_line(buf, binary, separator)),
+        Err(err) => Some(Err(err.into())),
+    }
+}
+
+/// Strip the line terminator from the bytes and decode them.
+fn decode_line(mut buf: Vec<u8>, binary: bool, separator: u8) -> Result<(String, bool)> {
+    let terminated = buf.last() == Some(&separator);
+    if terminated {
+        buf.pop();
+        if separator == b'\n' && buf.ends_with(b"\r") {
+            buf.pop();
+        }
+    }
+    let line = if binary {
+        decode_binary(&buf)
+    } else {
+        String::from_utf8(buf).map_err(|err| anyhow!("input is not valid UTF-8: {}", err))?
+    };
+    Ok((line, terminated))
+}
 
 pub struct StdinReader {
-    buffer: Lines<BufReader<std::io::Stdin>>,
+    buffer: BufReader<std::io::Stdin>,
     counter: usize,
+    missing_newline: Rc<Cell<bool>>,
+    binary: bool,
+    separator: u8,
+}
+
+impl StdinReader {
+    /// The flag set when the line that was read last has no trailing newline.
+    pub fn missing_newline(&self) -> Rc<Cell<bool>> {
+        self.missing_newline.clone()
+    }
+
+    /// When set, the input does not need to be valid UTF-8, see [`BinaryWriter`].
+    pub fn set_binary(&mut self, binary: bool) {
+        self.binary = binary;
+    }
+
+    /// Split the input into records on `separator` rather than the newlines.
+    pub fn set_separator(&mut self, separator: u8) {
+        self.separator = separator;
+    }
 }
 
 impl Default for StdinReader {
     fn default() -> Self {
         StdinReader {
-            buffer: BufReader::new(std::io::stdin()).lines(),
+            buffer: BufReader::new(std::io::stdin()),
             counter: 0,
+            missing_newline: Rc::default(),
+            binary: false,
+            separator: b'\n',
         }
     }
 }
@@ -26,42 +172,126 @@ impl Iterator for StdinReader {
     type Item = Result<Line>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.buffer.next()? {
-            Ok(line) => {
+        match read_line(&mut self.buffer, self.binary, self.separator)? {
+            Ok((mut line, terminated)) => {
+                self.missing_newline.set(!terminated);
+                if self.counter == 0 {
+                    line = strip_bom(line);
+                }
                 self.counter += 1;
-                let line = Line(self.counter, line.to_string());
+                let line = Line(self.counter, line.to_string(), None);
                 Some(Ok(line))
             }
-            Err(err) => Some(Err(err.into())),
+            Err(err) => Some(Err(err)),
         }
     }
 }
 
 pub struct FilesReader {
     paths: Vec<PathBuf>,
-    file: Option<Lines<BufReader<File>>>,
+    file: Option<Box<dyn BufRead>>,
+    path: Option<Arc<PathBuf>>,
+    first: bool,
     counter: usize,
+    missing_newline: Rc<Cell<bool>>,
+    binary: bool,
+    separator: u8,
+    mmap: bool,
 }
 
 impl FilesReader {
+    /// The flag set when the line that was read last has no trailing newline.
+    pub fn missing_newline(&self) -> Rc<Cell<bool>> {
+        self.missing_newline.clone()
+    }
+
+    /// When set, the input does not need to be valid UTF-8, see [`BinaryWriter`].
+    pub fn set_binary(&mut self, binary: bool) {
+        self.binary = binary;
+    }
+
+    /// Split the input into records on `separator` rather than the newlines.
+    pub fn set_separator(&mut self, separator: u8) {
+        self.separator = separator;
+    }
+
+    /// Read the `data` as if it was the content of the file at the `path`.
+    pub fn from_data(path: PathBuf, data: Vec<u8>) -> Self {
+        let mut reader = FilesReader::from(Vec::new());
+        reader.file = Some(Box::new(Cursor::new(data)));
+        reader.path = (!is_stdin(&path)).then(|| Arc::new(path));
+        reader.first = true;
+        reader
+    }
+
+    /// When set, the regular files are memory-mapped rather than read through a buffer.
+    /// The files must not be modified while they are read.
+    pub fn set_mmap(&mut self, mmap: bool) {
+        self.mmap = mmap;
+    }
+
     fn next_file(&mut self) -> Option<Result<()>> {
         let path = self.paths.pop()?;
-        let file = match File::open(path) {
-            Ok(file) => file,
-            Err(err) => return Some(Err(err.into())),
+        let reader = match open(&path, self.mmap) {
+            Ok(reader) => reader,
+            Err(err) => return Some(Err(err)),
         };
-        let reader = BufReader::new(file).lines();
         self.file = Some(reader);
+        self.path = if is_stdin(&path) {
+            None
+        } else {
+            Some(Arc::new(path))
+        };
+        self.first = true;
         Some(Ok(()))
     }
 }
 
+/// The `-` path stands for stdin.
+fn is_stdin(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+/// Open the file for reading, the `.gz` files are decompressed on the fly.
+/// The `-` path reads stdin.
+fn open(path: &PathBuf, mmap: bool) -> Result<Box<dyn BufRead>> {
+    if is_stdin(path) {
+        return Ok(Box::new(BufReader::new(std::io::stdin())));
+    }
+    let file = File::open(path)?;
+    if path.extension().is_some_and(|ext| ext == "gz") {
+        let mut reader = BufReader::new(MultiGzDecoder::new(file));
+        // read the header, so that invalid files fail early with a clear message
+        reader
+            .fill_buf()
+            .map_err(|err| anyhow!("{} is not a valid gzip file: {}", path.display(), err))?;
+        return Ok(Box::new(reader));
+    }
+    if mmap {
+        let metadata = file.metadata()?;
+        // empty files cannot be mapped, and there is nothing to gain for them anyway
+        if metadata.is_file() && metadata.len() > 0 {
+            // SAFETY: the file is not modified while it is read, see `set_mmap`
+            let map = unsafe { memmap2::Mmap::map(&file)? };
+            // the lines are copied straight from the map, without the intermediate buffer
+            return Ok(Box::new(Cursor::new(map)));
+        }
+    }
+    Ok(Box::new(BufReader::new(file)))
+}
+
 impl From<Vec<PathBuf>> for FilesReader {
     fn from(value: Vec<PathBuf>) -> Self {
         FilesReader {
             paths: value.iter().cloned().rev().collect(),
             file: None,
+            path: None,
+            first: false,
             counter: 0,
+            missing_newline: Rc::default(),
+            binary: false,
+            separator: b'\n',
+            mmap: false,
         }
     }
 }
@@ -72,13 +302,18 @@ impl Iterator for FilesReader {
     fn next(&mut self) -> Option<Self::Item> {
         loop {
             if let Some(ref mut buffer) = self.file {
-                match buffer.next() {
-                    Some(Ok(line)) => {
+                match read_line(buffer, self.binary, self.separator) {
+                    Some(Ok((mut line, terminated))) => {
+                        self.missing_newline.set(!terminated);
+                        if self.first {
+                            line = strip_bom(line);
+                            self.first = false;
+                        }
                         self.counter += 1;
-                        let line = Line(self.counter, line.to_string());
+                        let line = Line(self.counter, line.to_string(), self.path.clone());
                         return Some(Ok(line));
                     }
-                    Some(Err(err)) => return Some(Err(err.into())),
+                    Some(Err(err)) => return Some(Err(err)),
                     None => {
                         if let Err(err) = self.next_file()? {
                             return Some(Err(err));
@@ -92,6 +327,331 @@ impl Iterator for FilesReader {
     }
 }
 
+/// How long to wait before checking again for the lines appended to the followed file.
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Reader for a single file that, like `tail -f`, does not stop at the end of the file,
+/// but waits for the new lines appended to it. Only the complete (terminated) lines are read.
+/// When the file is truncated, or replaced by a new file (e.g. log rotation), it is read
+/// again from the start.
+pub struct FollowReader {
+    path: Arc<PathBuf>,
+    file: BufReader<File>,
+    position: u64,
+    pending: Vec<u8>,
+    counter: usize,
+    binary: bool,
+    separator: u8,
+}
+
+impl FollowReader {
+    pub fn new(path: PathBuf) -> Result<Self> {
+        let file = File::open(&path)?;
+        if !file.metadata()?.is_file() {
+            bail!(
+                "only regular files can be followed, {} is not",
+                path.display()
+            );
+        }
+        Ok(FollowReader {
+            path: Arc::new(path),
+            file: BufReader::new(file),
+            position: 0,
+            pending: Vec::new(),
+            counter: 0,
+            binary: false,
+            separator: b'\n',
+        })
+    }
+
+    /// When set, the input does not need to be valid UTF-8, see [`BinaryWriter`].
+    pub fn set_binary(&mut self, binary: bool) {
+        self.binary = binary;
+    }
+
+    /// Split the input into records on `separator` rather than the newlines.
+    pub fn set_separator(&mut self, separator: u8) {
+        self.separator = separator;
+    }
+
+    /// Start reading the file from the beginning if it was truncated or replaced.
+    fn reopen_if_changed(&mut self) -> Result<()> {
+        // during the rotation, the file may be missing for a moment
+        let Ok(current) = std::fs::metadata(self.path.as_ref()) else {
+            return Ok(());
+        };
+        let opened = self.file.get_ref().metadata()?;
+        if current.len() < self.position || !same_file(&opened, &current) {
+            self.file = BufReader::new(File::open(self.path.as_ref())?);
+            self.position = 0;
+            self.pending.clear();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn same_file(lhs: &std::fs::Metadata, rhs: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    lhs.dev() == rhs.dev() && lhs.ino() == rhs.ino()
+}
+
+#[cfg(not(unix))]
+fn same_file(_: &std::fs::Metadata, _: &std::fs::Metadata) -> bool {
+    true
+}
+
+impl Iterator for FollowReader {
+    type Item = Result<Line>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.file.read_until(self.separator, &mut self.pending) {
+                Ok(0) => {
+                    if let Err(err) = self.reopen_if_changed() {
+                        return Some(Err(err));
+                    }
+                    std::thread::sleep(FOLLOW_INTERVAL);
+                }
+                Ok(n) => {
+                    self.position += n as u64;
+                    // the partially written line is completed by the next reads
+                    if self.pending.last() != Some(&self.separator) {
+                        continue;
+                    }
+                    let buf = std::mem::take(&mut self.pending);
+                    return match decode_line(buf, self.binary, self.separator) {
+                        Ok((mut line, _)) => {
+                            if self.counter == 0 {
+                                line = strip_bom(line);
+                            }
+                            self.counter += 1;
+                            Some(Ok(Line(self.counter, line, Some(self.path.clone()))))
+                        }
+                        Err(err) => Some(Err(err)),
+                    };
+                }
+                Err(err) => return Some(Err(err.into())),
+            }
+        }
+    }
+}
+
+/// Reader joining the lines into records delimited by the lines matching the regular
+/// expression. The delimiting lines are not a part of the records, the lines in the
+/// record are joined with newlines. Empty records are skipped.
+pub struct RecordsReader<R: Iterator<Item = Result<Line>>> {
+    inner: R,
+    delimiter: regex::Regex,
+    counter: usize,
+}
+
+impl<R: Iterator<Item = Result<Line>>> RecordsReader<R> {
+    pub fn new(inner: R, delimiter: &str) -> Result<Self> {
+        Ok(RecordsReader {
+            inner,
+            delimiter: regex::Regex::new(delimiter)?,
+            counter: 0,
+        })
+    }
+}
+
+impl<R: Iterator<Item = Result<Line>>> Iterator for RecordsReader<R> {
+    type Item = Result<Line>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut record: Option<Line> = None;
+        for line in self.inner.by_ref() {
+            let line = match line {
+                Ok(line) => line,
+                Err(err) => return Some(Err(err)),
+            };
+            if self.delimiter.is_match(&line.1) {
+                if record.is_some() {
+                    break;
+                }
+                continue;
+            }
+            match record {
+                Some(ref mut record) => {
+                    record.1.push('\n');
+                    record.1.push_str(&line.1);
+                    record.2 = line.2;
+                }
+                None => record = Some(line),
+            }
+        }
+        let mut record = record?;
+        self.counter += 1;
+        record.0 = self.counter;
+        Some(Ok(record))
+    }
+}
+
+/// Writer handling the line terminators of the output. It follows the input, so that
+/// if the final line has no trailing newline, neither does the output. The newline at
+/// the end of the output written while processing such a line is held back, and it is
+/// written only if more output follows.
+pub struct NewlineWriter<W: Write> {
+    inner: W,
+    missing_newline: Rc<Cell<bool>>,
+    pending: bool,
+    crlf: bool,
+    separator: u8,
+}
+
+impl<W: Write> NewlineWriter<W> {
+    pub fn new(inner: W, missing_newline: Rc<Cell<bool>>) -> Self {
+        NewlineWriter {
+            inner,
+            missing_newline,
+            pending: false,
+            crlf: false,
+            separator: b'\n',
+        }
+    }
+
+    /// When set, the lines are terminated with `\r\n` rather than `\n`.
+    pub fn set_crlf(&mut self, crlf: bool) {
+        self.crlf = crlf;
+    }
+
+    /// The records are terminated with `separator` rather than the newline.
+    pub fn set_separator(&mut self, separator: u8) {
+        self.separator = separator;
+    }
+
+    fn write_newline(&mut self) -> std::io::Result<()> {
+        if self.separator != b'\n' {
+            return self.inner.write_all(&[self.separator]);
+        }
+        self.inner
+            .write_all(if self.crlf { b"\r\n" } else { b"\n" })
+    }
+}
+
+impl<W: Write> Write for NewlineWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pending {
+            self.write_newline()?;
+            self.pending = false;
+        }
+        let (body, newline) = match buf.split_last() {
+            Some((&b, rest)) if b == self.separator => (rest, true),
+            _ => (buf, false),
+        };
+        if self.crlf {
+            let mut chunks = body.split(|&b| b == b'\n').peekable();
+            while let Some(chunk) = chunks.next() {
+                self.inner.write_all(chunk)?;
+                if chunks.peek().is_some() {
+                    if chunk.ends_with(b"\r") {
+                        self.inner.write_all(b"\n")?;
+                    } else {
+                        self.write_newline()?;
+                    }
+                }
+            }
+        } else {
+            self.inner.write_all(body)?;
+        }
+        if newline {
+            if self.missing_newline.get() {
+                self.pending = true;
+            } else if self.separator == b'\n' && body.ends_with(b"\r") {
+                self.inner.write_all(b"\n")?;
+            } else {
+                self.write_newline()?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writer for the binary mode, where the bytes of the input that were not valid UTF-8
+/// are written back as they were. The characters split between the writes (e.g. when
+/// copying a file) are held until the rest of them arrives.
+pub struct BinaryWriter<W: Write> {
+    inner: W,
+    pending: Vec<u8>,
+}
+
+impl<W: Write> BinaryWriter<W> {
+    pub fn new(inner: W) -> Self {
+        BinaryWriter {
+            inner,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Write the text replacing the raw bytes characters with the bytes.
+    fn write_text(&mut self, s: &str) -> std::io::Result<()> {
+        if !s.chars().any(|c| raw_byte(c).is_some()) {
+            return self.inner.write_all(s.as_bytes());
+        }
+        let mut acc = Vec::with_capacity(s.len());
+        for c in s.chars() {
+            match raw_byte(c) {
+                Some(b) => acc.push(b),
+                None => acc.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            }
+        }
+        self.inner.write_all(&acc)
+    }
+}
+
+impl<W: Write> Write for BinaryWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut data = std::mem::take(&mut self.pending);
+        data.extend_from_slice(buf);
+        let mut rest = &data[..];
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(s) => {
+                    self.write_text(s)?;
+                    break;
+                }
+                Err(err) => {
+                    let (valid, invalid) = rest.split_at(err.valid_up_to());
+                    self.write_text(std::str::from_utf8(valid).unwrap())?;
+                    match err.error_len() {
+                        // the bytes that are not UTF-8 are written as-is
+                        Some(len) => {
+                            self.inner.write_all(&invalid[..len])?;
+                            rest = &invalid[len..];
+                        }
+                        // the character may be completed by the next write
+                        None => {
+                            self.pending = invalid.to_vec();
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let pending = std::mem::take(&mut self.pending);
+        self.inner.write_all(&pending)?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for BinaryWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.inner.write_all(&self.pending);
+    }
+}
+
 #[cfg(test)]
 pub(crate) struct MockReader {}
 
@@ -102,3 +662,233 @@ impl Iterator for MockReader {
         None
     }
 }
+
+/// File in the temporary directory used by the tests. The path is unique to the test,
+/// so the tests running in parallel (also in the other processes) do not share the files.
+/// The file is removed when it is dropped, also when the test fails.
+#[cfg(test)]
+pub(crate) struct TempFile(PathBuf);
+
+#[cfg(test)]
+impl TempFile {
+    pub(crate) fn new(name: &str, content: &str) -> Self {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let num = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("se-{}-{num}-{name}", std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        TempFile(path)
+    }
+
+    pub(crate) fn path(&self) -> &PathBuf {
+        &self.0
+    }
+
+    pub(crate) fn read(&self) -> String {
+        std::fs::read_to_string(&self.0).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, io::Write, rc::Rc, sync::mpsc, time::Duration};
+    use test_case::test_case;
+
+    #[test_case("\u{FEFF}foo", "foo"; "with bom")]
+    #[test_case("foo", "foo"; "without bom")]
+    #[test_case("foo\u{FEFF}", "foo\u{FEFF}"; "not at the start")]
+    fn strip_bom(input: &str, expected: &str) {
+        assert_eq!(super::strip_bom(input.to_string()), expected)
+    }
+
+    #[test_case("a\nb\n", vec![("a", true), ("b", true)]; "terminated")]
+    #[test_case("a\nb", vec![("a", true), ("b", false)]; "missing newline")]
+    #[test_case("a\r\nb\r\n", vec![("a", true), ("b", true)]; "crlf")]
+    #[test_case("\n\n", vec![("", true), ("", true)]; "empty lines")]
+    fn read_line(input: &str, expected: Vec<(&str, bool)>) {
+        let mut reader = input.as_bytes();
+        let mut result = Vec::new();
+        while let Some(line) = super::read_line(&mut reader, false, b'\n') {
+            result.push(line.unwrap());
+        }
+        let expected: Vec<(String, bool)> = expected
+            .into_iter()
+            .map(|(s, t)| (s.to_string(), t))
+            .collect();
+        assert_eq!(result, expected)
+    }
+
+    #[test]
+    fn newline_writer() {
+        let missing_newline = Rc::new(Cell::new(false));
+        let mut out = super::NewlineWriter::new(Vec::new(), missing_newline.clone());
+        writeln!(out, "a").unwrap();
+        missing_newline.set(true);
+        writeln!(out, "b").unwrap();
+        writeln!(out, "b").unwrap();
+        assert_eq!(out.inner, b"a\nb\nb")
+    }
+
+    #[test_case("a\n", "a\r\n"; "line")]
+    #[test_case("a\nb\n", "a\r\nb\r\n"; "many lines")]
+    #[test_case("a\r\nb\r\n", "a\r\nb\r\n"; "already crlf")]
+    #[test_case("a", "a"; "no newline")]
+    fn crlf(input: &str, expected: &str) {
+        let mut out = super::NewlineWriter::new(Vec::new(), Rc::default());
+        out.set_crlf(true);
+        write!(out, "{input}").unwrap();
+        assert_eq!(String::from_utf8(out.inner).unwrap(), expected)
+    }
+
+    #[test_case("a;b\n;c", vec![("a", true), ("b\n", true), ("c", false)]; "semicolon")]
+    #[test_case("a\r;", vec![("a\r", true)]; "carriage return is kept")]
+    fn read_records(input: &str, expected: Vec<(&str, bool)>) {
+        let mut reader = input.as_bytes();
+        let mut result = Vec::new();
+        while let Some(line) = super::read_line(&mut reader, false, b';') {
+            result.push(line.unwrap());
+        }
+        let expected: Vec<(String, bool)> = expected
+            .into_iter()
+            .map(|(s, t)| (s.to_string(), t))
+            .collect();
+        assert_eq!(result, expected)
+    }
+
+    #[test]
+    fn separator_writer() {
+        let missing_newline = Rc::new(Cell::new(false));
+        let mut out = super::NewlineWriter::new(Vec::new(), missing_newline.clone());
+        out.set_separator(b';');
+        write!(out, "a\n;").unwrap();
+        missing_newline.set(true);
+        write!(out, "b;").unwrap();
+        assert_eq!(out.inner, b"a\n;b")
+    }
+
+    #[test_case("a\nb\n\nc\n", vec!["a\nb", "c"]; "paragraphs")]
+    #[test_case("\n\na\n\n\n\nb\n\n", vec!["a", "b"]; "empty records")]
+    #[test_case("a\nb", vec!["a\nb"]; "no delimiter")]
+    #[test_case("", vec![]; "empty")]
+    fn records(input: &str, expected: Vec<&str>) {
+        let lines = input
+            .lines()
+            .enumerate()
+            .map(|(i, s)| Ok(super::Line(i + 1, s.to_string(), None)));
+        let result: Vec<super::Line> = super::RecordsReader::new(lines, "^$")
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        let expected: Vec<super::Line> = expected
+            .into_iter()
+            .enumerate()
+            .map(|(i, s)| super::Line(i + 1, s.to_string(), None))
+            .collect();
+        assert_eq!(result, expected)
+    }
+
+    #[test]
+    fn crlf_input_without_flag() {
+        // the line endings of the input are not preserved, without `--crlf` the output uses `\n`
+        let mut reader = "a\r\nb\r\n".as_bytes();
+        let mut out = super::NewlineWriter::new(Vec::new(), Rc::default());
+        while let Some(line) = super::read_line(&mut reader, false, b'\n') {
+            writeln!(out, "{}", line.unwrap().0).unwrap();
+        }
+        assert_eq!(out.inner, b"a\nb\n")
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        let mut reader = &b"a\xffb\n"[..];
+        assert!(
+            super::read_line(&mut reader, false, b'\n')
+                .unwrap()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn follow() {
+        let file = super::TempFile::new("follow.txt", "a\nb");
+        let path = file.path();
+        let reader = super::FollowReader::new(path.clone()).unwrap();
+        // the reader never ends, so it runs in the background until the receiver is gone
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in reader {
+                let line = line.unwrap();
+                if sender.send((line.0, line.1)).is_err() {
+                    break;
+                }
+            }
+        });
+        let next = || receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(next(), (1, "a".to_string()));
+
+        // the partial line waits for the rest of it
+        let mut file = std::fs::OpenOptions::new().append(true).open(path).unwrap();
+        write!(file, "c\nd\n").unwrap();
+        assert_eq!(next(), (2, "bc".to_string()));
+        assert_eq!(next(), (3, "d".to_string()));
+
+        // truncated file is read from the start
+        std::fs::write(path, "e\n").unwrap();
+        assert_eq!(next(), (4, "e".to_string()));
+    }
+
+    #[test_case(""; "empty")]
+    #[test_case("a\nb\r\n\nc"; "lines")]
+    #[test_case("\u{FEFF}zażółć\ngęślą\n"; "utf8 with bom")]
+    fn mmap(content: &str) {
+        let file = super::TempFile::new("mmap.txt", content);
+        let read = |mmap| {
+            let mut reader = super::FilesReader::from(vec![file.path().clone()]);
+            reader.set_mmap(mmap);
+            reader.map(Result::unwrap).collect::<Vec<_>>()
+        };
+        assert_eq!(read(true), read(false));
+    }
+
+    #[test_case(b"abc"; "ascii")]
+    #[test_case("zażółć".as_bytes(); "utf8")]
+    #[test_case(b"a\xffb\x80\xc3"; "invalid utf8")]
+    #[test_case(b"\xef\xbf\xbd"; "replacement character")]
+    #[test_case("a\u{10FF41}\u{10FFFF}b".as_bytes(); "characters used for raw bytes")]
+    #[test_case("\u{10FEFF}\u{10FFFF}\u{10FF00}".as_bytes(); "next to the raw bytes range")]
+    fn binary(input: &[u8]) {
+        let mut reader = input;
+        let (line, _) = super::read_line(&mut reader, true, b'\n').unwrap().unwrap();
+        let mut out = super::BinaryWriter::new(Vec::new());
+        write!(out, "{line}").unwrap();
+        out.flush().unwrap();
+        assert_eq!(out.inner, input)
+    }
+
+    #[test]
+    fn binary_inserted_raw_byte_character() {
+        // the characters from the range that come from the script collide with the raw bytes
+        let mut out = super::BinaryWriter::new(Vec::new());
+        write!(out, "a\u{10FFFF}").unwrap();
+        assert_eq!(out.inner, b"a\xff");
+    }
+
+    #[test]
+    fn binary_split_character() {
+        let input = "ż\u{10FFFF}ł".as_bytes();
+        for i in 0..input.len() {
+            let mut out = super::BinaryWriter::new(Vec::new());
+            out.write_all(&input[..i]).unwrap();
+            out.write_all(&input[i..]).unwrap();
+            out.flush().unwrap();
+            assert_eq!(out.inner, b"\xc5\xbc\xff\xc5\x82", "split at {i}");
+        }
+    }
+}
@@ -1,75 +1,512 @@
-use anyhow::Result;
-use clap::Parser;
-use se::{FilesReader, Line, Program, Status, StdinReader};
-use std::{io::Write, path::PathBuf, str::FromStr};
+use anyhow::{Result, anyhow, bail};
+use clap::{CommandFactory, FromArgMatches, Parser, ValueEnum};
+use se::{
+    BOM, BinaryWriter, FilesReader, FollowReader, Line, NewlineWriter, Program, RecordsReader,
+    RegexOptions, Script, Status, StdinReader,
+};
+use std::{
+    cell::Cell,
+    fs::File,
+    io::{BufWriter, ErrorKind, IsTerminal, Write},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+};
 
 fn main() -> Result<()> {
     let args = parse_args();
+    let closed = Rc::default();
+    match run(&args, &closed) {
+        // like the other filters, stop quietly when the reader of the output is gone (e.g. `head`)
+        Err(err) if closed.get() && is_broken_pipe(&err) => Ok(()),
+        result => result,
+    }
+}
+
+fn run(args: &Args, closed: &Rc<Cell<bool>>) -> Result<()> {
+    let (mut program, data) = Program::parse_scripts_with_data(&args.scripts, args.fixed_strings)?;
+    if args.paths.iter().any(|path| path.as_os_str() == "-")
+        && ((args.files.is_empty() && data.is_none())
+            || args.files.iter().any(|file| file.as_os_str() == "-"))
+    {
+        bail!("the script is read from stdin (-f -), so the input cannot be read from it");
+    }
+
+    program.set_regex_options(RegexOptions {
+        dotall: args.dotall,
+        multiline: args.multiline,
+    })?;
+    if let Some(width) = args.line_length {
+        program.set_line_wrap(width);
+    }
+    // the followed file never ends, so the output cannot wait for it
+    program.set_unbuffered(args.unbuffered || args.follow);
+    if args.dump {
+        let mut out = PipeWriter::new(std::io::stdout().lock(), closed.clone());
+        write!(out, "{program}")?;
+        return Ok(());
+    }
+    if args.trace {
+        program.set_trace(std::io::stderr());
+    }
+    program.set_expand_env(args.expand_env);
+    if let Some(hold) = &args.hold {
+        program.set_hold(hold);
+    }
+    program.set_json(args.json);
+    program.set_binary(args.binary);
+    program.set_count_by_rule(args.count_by_rule || args.warn_unused);
+    program.set_color(match args.color {
+        Color::Auto => args.output.is_none() && std::io::stdout().is_terminal(),
+        Color::Always => true,
+        Color::Never => false,
+    });
+    if args.record_sep != b'\n' {
+        program.set_separator(args.record_sep as char);
+    }
 
-    let mut program = if let Some(path) = &args.script.path {
-        Program::try_from(path)?
-    } else if let Some(command) = &args.script.command {
-        Program::from_str(command)?
+    let (mut reader, missing_newline): (Box<dyn Iterator<Item = Result<Line>>>, _) = if args.follow
+    {
+        let [path] = &args.files[..] else {
+            bail!("--follow needs exactly one file");
+        };
+        let mut reader = FollowReader::new(path.clone())?;
+        reader.set_binary(args.binary);
+        reader.set_separator(args.record_sep);
+        // only the terminated lines are read
+        (Box::new(reader), Default::default())
+    } else if let Some(data) = data
+        && args.files.is_empty()
+    {
+        // the inline data following `__END__` in the script file
+        let path = args.paths.last().cloned().unwrap_or_default();
+        let mut reader = FilesReader::from_data(path, data);
+        reader.set_binary(args.binary);
+        reader.set_separator(args.record_sep);
+        let missing_newline = reader.missing_newline();
+        (Box::new(reader), missing_newline)
+    } else if args.files.is_empty() {
+        let mut reader = StdinReader::default();
+        reader.set_binary(args.binary);
+        reader.set_separator(args.record_sep);
+        let missing_newline = reader.missing_newline();
+        (Box::new(reader), missing_newline)
     } else {
-        unreachable!()
+        let mut reader = FilesReader::from(args.files.clone());
+        reader.set_binary(args.binary);
+        reader.set_separator(args.record_sep);
+        reader.set_mmap(args.mmap);
+        let missing_newline = reader.missing_newline();
+        (Box::new(reader), missing_newline)
     };
 
-    let mut reader: Box<dyn Iterator<Item = Result<Line>>> = if args.files.is_empty() {
-        Box::new(StdinReader::default())
+    if let Some(delimiter) = &args.record_regex {
+        reader = Box::new(RecordsReader::new(reader, delimiter)?);
+    }
+
+    if args.dry_run {
+        return program.dry_run(&mut reader, &mut std::io::stderr().lock());
+    }
+
+    let stdout: Box<dyn Write> = match &args.output {
+        Some(path) => {
+            if args.files.iter().any(|file| same_path(file, path)) {
+                bail!("the output file {} is also an input file", path.display());
+            }
+            let file = File::create(path)
+                .map_err(|err| anyhow!("cannot write to {}: {}", path.display(), err))?;
+            Box::new(BufWriter::new(file))
+        }
+        None => Box::new(BufWriter::new(PipeWriter::new(
+            std::io::stdout().lock(),
+            closed.clone(),
+        ))),
+    };
+    let stdout: Box<dyn Write> = if args.binary {
+        Box::new(BinaryWriter::new(stdout))
     } else {
-        Box::new(FilesReader::from(args.files))
+        Box::new(stdout)
+    };
+    // the records are always terminated, regardless of the input
+    let withhold_newline = if args.json || args.files_with_matches {
+        Default::default()
+    } else {
+        missing_newline.clone()
+    };
+    let out = &mut NewlineWriter::new(stdout, withhold_newline);
+    out.set_crlf(args.crlf);
+    out.set_separator(args.record_sep);
+    if args.bom {
+        write!(out, "{BOM}")?;
+    }
+    let (status, count) = if args.files_with_matches {
+        program.files_with_matches(&mut reader, out)?
+    } else if let Some(jobs) = args.jobs
+        && !args.files.is_empty()
+    {
+        run_jobs(&program, args, jobs.get(), &missing_newline, out)?
+    } else {
+        program.run(&mut reader, args.all, args.max_count, out)?
     };
-
-    let out = &mut std::io::stdout().lock();
-    let (status, count) = program.run(&mut reader, args.all, out)?;
 
     if args.count {
+        // the count is not a part of the input
+        missing_newline.set(false);
         writeln!(out, "{count}")?;
     }
-    if let Status::Quit(code) = status {
+    out.flush()?;
+    if args.count_by_rule {
+        program.write_rule_counts(&mut std::io::stderr().lock())?;
+    }
+    if args.warn_unused {
+        program.write_unused_rules(&mut std::io::stderr().lock())?;
+    }
+    // the explicit exit code of `q` wins over the match-based status
+    if let Some(code) = status.exit_code() {
         std::process::exit(code)
     }
+    if args.match_status && count == 0 {
+        std::process::exit(1)
+    }
     Ok(())
 }
 
 #[derive(Parser)]
+#[command(
+    override_usage = "se [OPTIONS] <SCRIPT> [FILE]...\n       se [OPTIONS] <-f PATH | -e SCRIPT> [FILE]..."
+)]
 struct Args {
     /// Print all the lines (except the ones that were deleted)
-    #[arg(short, long)]
+    #[arg(short, long, overrides_with = "quiet")]
     all: bool,
 
+    /// Print only what the commands print, it is the default, but it overrides the earlier `-a`
+    #[arg(short = 'n', long, overrides_with = "all")]
+    quiet: bool,
+
+    /// Write the output to the file rather than stdout
+    #[arg(short, long, value_name = "FILE", conflicts_with = "dry_run")]
+    output: Option<PathBuf>,
+
     /// Print the number of matches
     #[arg(short, long)]
     count: bool,
 
-    #[command(flatten)]
-    script: Script,
+    /// Stop reading the input after N matching lines
+    #[arg(short, long, value_name = "N")]
+    max_count: Option<usize>,
+
+    /// Like `grep`, exit with the status 1 when none of the lines were matched
+    /// (unless the exit code was set by `q`)
+    #[arg(long, conflicts_with_all = ["follow", "dry_run"])]
+    match_status: bool,
+
+    /// Print only the names of the files having at least one matching line
+    #[arg(long, conflicts_with_all = ["all", "count", "max_count", "json"])]
+    files_with_matches: bool,
+
+    /// Like `tail -f`, do not stop at the end of the FILE, but wait for the lines appended
+    /// to it (the finally block is never run)
+    #[arg(long, conflicts_with_all = ["files_with_matches", "dry_run", "count"])]
+    follow: bool,
+
+    /// Process the FILEs in N parallel threads, each of them separately, so the line numbers
+    /// restart for each file, and the ranges, registers, and the finally block do not carry
+    /// over between the files
+    #[arg(
+        short,
+        long,
+        value_name = "N",
+        conflicts_with_all = ["follow", "files_with_matches", "max_count", "count_by_rule", "warn_unused", "trace"]
+    )]
+    jobs: Option<NonZeroUsize>,
+
+    /// Line-wrap width for the `l` command (0 means no wrapping)
+    #[arg(short, long, value_name = "N")]
+    line_length: Option<usize>,
+
+    /// Flush the output after processing each line
+    #[arg(short, long)]
+    unbuffered: bool,
+
+    /// Terminate the output lines with CRLF (the CR characters are always stripped from the input)
+    #[arg(long)]
+    crlf: bool,
+
+    /// Accept input that is not valid UTF-8, the invalid bytes are passed to the output as-is
+    #[arg(long)]
+    binary: bool,
+
+    /// Split the input into records on the character rather than the newlines,
+    /// it is also used to terminate the printed records (`\0` works like `sed -z`)
+    #[arg(long, value_name = "CHAR", default_value = "\\n", value_parser = parse_separator)]
+    record_sep: u8,
+
+    /// Memory-map the FILEs rather than reading them through a buffer, which may be faster
+    /// for big files (they must not be modified in the meantime)
+    #[arg(long, conflicts_with = "follow")]
+    mmap: bool,
+
+    /// Treat the regular expressions and the replacements of the substitutions as literal strings
+    #[arg(short = 'F', long)]
+    fixed_strings: bool,
+
+    /// Make `.` in the regular expressions match also the newlines, like the `(?s)` flag
+    #[arg(long)]
+    dotall: bool,
+
+    /// Make `^` and `$` in the regular expressions match at the start and end of each line
+    /// of the pattern space, like the `(?m)` flag
+    #[arg(long)]
+    multiline: bool,
+
+    /// Join the lines into records delimited by the lines matching the regular expression
+    #[arg(long, value_name = "REGEX")]
+    record_regex: Option<String>,
+
+    /// Do not run the commands, report to stderr the matched addresses
+    /// and what the substitutions, deletions, and prints would do
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Report to stderr how many lines were matched by each of the addresses
+    #[arg(long)]
+    count_by_rule: bool,
 
-    /// Files that are processed
+    /// Warn on stderr about the addresses that did not match any line
+    #[arg(long)]
+    warn_unused: bool,
+
+    /// Log the evaluated addresses and commands for each line to stderr
+    #[arg(long)]
+    trace: bool,
+
+    /// Print the parsed script and exit without reading the input
+    #[arg(long)]
+    dump: bool,
+
+    /// Expand the environment variables like `$HOME` or `${HOME}` in the printed strings
+    #[arg(long)]
+    expand_env: bool,
+
+    /// Initial content of the hold space (with `--jobs`, each of the FILEs starts with it)
+    #[arg(long, value_name = "INIT")]
+    hold: Option<String>,
+
+    /// Highlight the parts of the printed lines matched by the regular expression addresses
+    #[arg(
+        long,
+        value_name = "WHEN",
+        default_value = "never",
+        default_missing_value = "auto",
+        num_args = 0..=1,
+        require_equals = true
+    )]
+    color: Color,
+
+    /// Print the lines as JSON lines records `{"line": N, "text": "..."}`
+    #[arg(long, conflicts_with = "color")]
+    json: bool,
+
+    /// Start the output with the UTF-8 byte order mark
+    #[arg(long)]
+    bom: bool,
+
+    /// Commands that are executed (with `-f` or `-e`, it is the first of the FILEs)
+    #[arg(
+        name = "SCRIPT",
+        allow_hyphen_values = true,
+        required_unless_present_any = ["paths", "expression"]
+    )]
+    command: Option<String>,
+
+    /// Read the commands from the file (`-` for stdin), when repeated or used together
+    /// with `-e`, the scripts are joined in the order they were given
+    #[arg(short = 'f', long = "file", value_name = "PATH")]
+    paths: Vec<PathBuf>,
+
+    /// Commands that are executed, passed as an option rather than the SCRIPT, when repeated
+    /// or used together with `-f`, the scripts are joined in the order they were given
+    #[arg(short = 'e', long, value_name = "SCRIPT", allow_hyphen_values = true)]
+    expression: Vec<String>,
+
+    /// Parts of the script from the SCRIPT, `-f`, and `-e` arguments, in order
+    #[arg(skip)]
+    scripts: Vec<Script>,
+
+    /// Files that are processed (`-` for stdin, which is read when no files are given)
     #[arg(name = "FILE")]
     files: Vec<PathBuf>,
 }
 
-#[derive(Parser)]
-#[group(multiple = true, required = true)]
-struct Script {
-    /// Commands that are executed
-    #[arg(allow_hyphen_values = true)]
-    command: Option<String>,
-
-    /// Read the commands from the file
-    #[arg(short = 'f', long = "file")]
-    path: Option<PathBuf>,
+#[derive(Clone, ValueEnum)]
+enum Color {
+    /// Only when printing to the terminal
+    Auto,
+    Always,
+    Never,
 }
 
+/// When the script is given with `-f` or `-e`, all the positional arguments are files,
+/// otherwise the first one is the script.
 fn parse_args() -> Args {
-    let mut args = Args::parse();
-    if args.script.path.is_some()
-        && let Some(arg) = args.script.command
-    {
-        // it's not a command, dumbo
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    if args.paths.is_empty() && args.expression.is_empty() {
+        args.scripts = args.command.take().map(Script::Text).into_iter().collect();
+        return args;
+    }
+    // clap assigns the first positional argument to the script
+    if let Some(arg) = args.command.take() {
         args.files.insert(0, arg.into());
-        args.script.command = None;
     }
+    // the scripts given with `-f` and `-e` are joined in the command line order
+    let indices = |id| matches.indices_of(id).into_iter().flatten();
+    let mut scripts: Vec<_> = (indices("paths").zip(args.paths.iter().cloned().map(Script::File)))
+        .chain(indices("expression").zip(args.expression.iter().cloned().map(Script::Text)))
+        .collect();
+    scripts.sort_by_key(|(idx, _)| *idx);
+    args.scripts = scripts.into_iter().map(|(_, script)| script).collect();
     args
 }
+
+/// Run the copies of the `program` over the files in `jobs` threads. The outputs are
+/// buffered and written in the order of the files. Like when reading the files one
+/// after another, the output stops at the first file where the program quit, and its
+/// status is the final one, otherwise it is the status of the last file.
+fn run_jobs<W: Write>(
+    program: &Program,
+    args: &Args,
+    jobs: usize,
+    missing_newline: &Cell<bool>,
+    out: &mut W,
+) -> Result<(Status, usize)> {
+    let next = AtomicUsize::new(0);
+    let quit = AtomicBool::new(false);
+    let results: Vec<Mutex<Option<Result<FileOutput>>>> =
+        args.files.iter().map(|_| Mutex::default()).collect();
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.min(args.files.len()) {
+            scope.spawn(|| {
+                // the files are taken in order, so the ones after a quit are not needed
+                while !quit.load(Ordering::Relaxed) {
+                    let idx = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(path) = args.files.get(idx) else {
+                        break;
+                    };
+                    let result = run_file(program.clone(), path, args);
+                    if matches!(&result, Ok(output) if output.status.is_quit()) {
+                        quit.store(true, Ordering::Relaxed);
+                    }
+                    *results[idx].lock().unwrap() = Some(result);
+                }
+            });
+        }
+    });
+
+    let mut status = Status::Normal;
+    let mut count = 0;
+    for result in results {
+        let output = result.into_inner().unwrap().unwrap()?;
+        // the newline is withheld only if nothing more follows, as with a single reader
+        missing_newline.set(output.missing_newline);
+        out.write_all(&output.buffer)?;
+        count += output.count;
+        status = output.status;
+        if status.is_quit() {
+            break;
+        }
+    }
+    Ok((status, count))
+}
+
+/// Output of processing a single file by [`run_jobs`].
+struct FileOutput {
+    buffer: Vec<u8>,
+    missing_newline: bool,
+    status: Status,
+    count: usize,
+}
+
+fn run_file(mut program: Program, path: &Path, args: &Args) -> Result<FileOutput> {
+    let mut reader = FilesReader::from(vec![path.to_path_buf()]);
+    reader.set_binary(args.binary);
+    reader.set_separator(args.record_sep);
+    reader.set_mmap(args.mmap);
+    let missing_newline = reader.missing_newline();
+    let mut reader: Box<dyn Iterator<Item = Result<Line>>> = Box::new(reader);
+    if let Some(delimiter) = &args.record_regex {
+        reader = Box::new(RecordsReader::new(reader, delimiter)?);
+    }
+    let mut buffer = Vec::new();
+    let (status, count) = program.run(&mut reader, args.all, None, &mut buffer)?;
+    Ok(FileOutput {
+        buffer,
+        missing_newline: missing_newline.get(),
+        status,
+        count,
+    })
+}
+
+/// Writer setting the `closed` flag when the write fails because the other end
+/// of the pipe was closed.
+struct PipeWriter<W: Write> {
+    inner: W,
+    closed: Rc<Cell<bool>>,
+}
+
+impl<W: Write> PipeWriter<W> {
+    fn new(inner: W, closed: Rc<Cell<bool>>) -> Self {
+        PipeWriter { inner, closed }
+    }
+
+    fn check<T>(&self, result: std::io::Result<T>) -> std::io::Result<T> {
+        if let Err(err) = &result
+            && err.kind() == ErrorKind::BrokenPipe
+        {
+            self.closed.set(true);
+        }
+        result
+    }
+}
+
+impl<W: Write> Write for PipeWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let result = self.inner.write(buf);
+        self.check(result)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let result = self.inner.flush();
+        self.check(result)
+    }
+}
+
+/// Was the error caused by writing to a closed pipe?
+fn is_broken_pipe(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|err| err.kind() == ErrorKind::BrokenPipe)
+    })
+}
+
+/// Do the paths point to the same existing file?
+fn same_path(lhs: &Path, rhs: &Path) -> bool {
+    match (lhs.canonicalize(), rhs.canonicalize()) {
+        (Ok(lhs), Ok(rhs)) => lhs == rhs,
+        _ => false,
+    }
+}
+
+/// The record separator needs to be a single byte, escape sequences like `\0` are allowed.
+fn parse_separator(s: &str) -> Result<u8, String> {
+    match se::unescape(s).as_deref().map(str::as_bytes) {
+        Ok(&[b]) => Ok(b),
+        _ => Err(format!("expected a single byte character, got '{s}'")),
+    }
+}
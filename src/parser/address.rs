@@ -1,33 +1,34 @@
 use super::{
+    Error,
     reader::Reader,
-    utils::{parse_regex, read_integer, skip_line, skip_whitespace},
+    utils::{
+        RegexContext, is_verbose, parse_regex, read_identifier, read_integer, skip_comments,
+        skip_whitespace,
+    },
 };
 use crate::address::{
     self,
     Address::{self, *},
+    Comparison,
 };
 use anyhow::{Result, bail};
+use std::collections::BTreeSet;
 
-pub(crate) fn parse<R: Reader>(reader: &mut R) -> Result<Address> {
+pub(crate) fn parse<R: Reader>(reader: &mut R, ctx: &mut RegexContext) -> Result<Address> {
     let mut addrs = Vec::new();
     let mut has_any = false;
     loop {
-        if reader.next_is('#')? {
-            skip_line(reader);
-            skip_whitespace(reader);
-            continue;
-        }
-        let mut addr = address(reader)?;
+        skip_comments(reader)?;
+        let mut addr = address(reader, ctx)?;
         match addr {
             Always => has_any = true,
             Set(ref mut rhs) => addrs.append(rhs),
+            Lines(ref lines) => addrs.extend(lines.iter().map(|&idx| Location(idx))),
             _ => addrs.push(addr),
         }
 
-        skip_whitespace(reader);
-        if reader.next_is(',')? {
-            skip_whitespace(reader);
-        } else {
+        skip_comments(reader)?;
+        if !reader.next_is(',')? {
             break;
         }
     }
@@ -39,21 +40,56 @@ pub(crate) fn parse<R: Reader>(reader: &mut R) -> Result<Address> {
     if addrs.len() == 1 {
         return Ok(addrs.remove(0));
     }
+    if let Some(regex) = fuse_regexes(&addrs) {
+        return Ok(Fused(regex, addrs));
+    }
+    if let Some(lines) = line_numbers(&addrs) {
+        return Ok(Lines(lines));
+    }
     Ok(Set(addrs))
 }
 
-fn address<R: Reader>(reader: &mut R) -> Result<Address> {
+/// Collect a set of line numbers for the lookups faster than scanning it.
+fn line_numbers(addrs: &[Address]) -> Option<BTreeSet<usize>> {
+    addrs
+        .iter()
+        .map(|addr| match addr {
+            Location(idx) => Some(*idx),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Combine a set of regular expressions into a single alternation,
+/// since matching one regex is faster than trying them one by one.
+fn fuse_regexes(addrs: &[Address]) -> Option<crate::Regex> {
+    let mut patterns = Vec::new();
+    for addr in addrs {
+        match addr {
+            // each pattern is wrapped in a group, so the anchors and flags apply only to it,
+            // in verbose mode a comment could swallow the closing bracket of the group,
+            // and the capture groups would be renumbered (or their names would clash)
+            Regex(regex) if !is_verbose(regex.0.as_str()) && regex.0.captures_len() == 1 => {
+                patterns.push(format!("(?:{})", regex.0.as_str()))
+            }
+            _ => return None,
+        }
+    }
+    // e.g. the fused regex may exceed the size limit, then the set is matched as-is
+    patterns.join("|").parse().ok()
+}
+
+fn address<R: Reader>(reader: &mut R, ctx: &mut RegexContext) -> Result<Address> {
     let negated = reader.next_is('!')?;
     skip_whitespace(reader);
     let addr = if reader.next_is('(')? {
-        skip_whitespace(reader);
-        let addr = parse(reader)?;
-        skip_whitespace(reader);
+        let addr = parse(reader, ctx)?;
+        skip_comments(reader)?;
         reader.expect(')')?;
         addr
     } else {
-        let addr = parse_range(reader)?;
-        skip_whitespace(reader);
+        let addr = parse_range(reader, ctx)?;
+        skip_comments(reader)?;
         addr
     };
     if negated {
@@ -62,13 +98,13 @@ fn address<R: Reader>(reader: &mut R) -> Result<Address> {
     Ok(addr)
 }
 
-fn parse_range<R: Reader>(reader: &mut R) -> Result<Address> {
-    let addr = atom(reader)?;
-    skip_whitespace(reader);
+fn parse_range<R: Reader>(reader: &mut R, ctx: &mut RegexContext) -> Result<Address> {
+    let addr = atom(reader, ctx)?;
+    skip_comments(reader)?;
     if reader.next_is('-')? {
         let lhs = addr.unwrap_or(Location(1));
-        skip_whitespace(reader);
-        let rhs = atom(reader)?.unwrap_or(Final);
+        skip_comments(reader)?;
+        let rhs = atom(reader, ctx)?.unwrap_or(Final);
         if let (Location(lo), Location(hi)) = (&lhs, &rhs)
             && lo > hi
         {
@@ -79,11 +115,11 @@ fn parse_range<R: Reader>(reader: &mut R) -> Result<Address> {
     Ok(addr.unwrap_or(Always))
 }
 
-fn atom<R: Reader>(reader: &mut R) -> Result<Option<Address>> {
+fn atom<R: Reader>(reader: &mut R, ctx: &mut RegexContext) -> Result<Option<Address>> {
     if let Some(c) = reader.peek()? {
         match c {
             '/' | '^' => {
-                let addr = match parse_regex(reader)? {
+                let addr = match parse_regex(reader, ctx)? {
                     Some(regex) => Regex(regex),
                     None => Always,
                 };
@@ -109,16 +145,84 @@ fn atom<R: Reader>(reader: &mut R) -> Result<Option<Address>> {
                 reader.skip();
                 return Ok(Some(Maybe));
             }
+            'f' => return Ok(Some(parse_field(reader, ctx)?)),
+            // otherwise it is the `l` command
+            'l' if reader.starts_with("len<")?
+                || reader.starts_with("len>")?
+                || reader.starts_with("len=")? =>
+            {
+                return Ok(Some(parse_length(reader)?));
+            }
             _ => (),
         }
     }
     Ok(None)
 }
 
+fn parse_field<R: Reader>(reader: &mut R, ctx: &mut RegexContext) -> Result<Address> {
+    // Parse: field(N [-dC])/regex/
+    let name = read_identifier(reader)?;
+    if name != "field" {
+        bail!("unexpected '{}'", name);
+    }
+    reader.expect('(')?;
+    skip_whitespace(reader);
+    let s = read_integer(reader)?;
+    if s.is_empty() {
+        bail!("missing field index");
+    }
+    let idx: usize = s.parse()?;
+    if idx == 0 {
+        bail!("field indexes need to be >0");
+    }
+    skip_whitespace(reader);
+    let sep = if reader.next_is('-')? {
+        reader.expect('d')?;
+        let Some(c) = reader.next()? else {
+            bail!(Error::EndOfInput)
+        };
+        skip_whitespace(reader);
+        Some(c)
+    } else {
+        None
+    };
+    reader.expect(')')?;
+    if !matches!(reader.peek()?, Some('/' | '^')) {
+        bail!("field address expects a regular expression");
+    }
+    match parse_regex(reader, ctx)? {
+        Some(regex) => Ok(FieldRegex(idx - 1, sep, regex)),
+        None => bail!("no previous regular expression"),
+    }
+}
+
+fn parse_length<R: Reader>(reader: &mut R) -> Result<Address> {
+    // Parse: len==N, len<N, len>N, len<=N, or len>=N
+    read_identifier(reader)?;
+    let op = match reader.next()? {
+        Some('=') if reader.next_is('=')? => Comparison::Equal,
+        Some('<') if reader.next_is('=')? => Comparison::LessOrEqual,
+        Some('<') => Comparison::Less,
+        Some('>') if reader.next_is('=')? => Comparison::GreaterOrEqual,
+        Some('>') => Comparison::Greater,
+        _ => bail!("invalid comparison, expected ==, <, >, <=, or >="),
+    };
+    skip_whitespace(reader);
+    let s = read_integer(reader)?;
+    if s.is_empty() {
+        bail!("missing line length");
+    }
+    Ok(Length(op, s.parse()?))
+}
+
 #[cfg(test)]
 mod tests {
-    use super::Address::{self, *};
+    use super::{
+        Address::{self, *},
+        Comparison,
+    };
     use crate::{address, parser::StringReader};
+    use std::{collections::BTreeSet, str::FromStr};
     use test_case::test_case;
 
     #[test_case("", Always; "empty")]
@@ -130,11 +234,110 @@ mod tests {
     #[test_case("!1-5", Negate(Box::new(Between(address::Between::new(Location(1), Location(5))))); "negated range")]
     #[test_case("(!(1-5))", Negate(Box::new(Between(address::Between::new(Location(1), Location(5))))); "brackets and negated range")]
     #[test_case("1,$", Set(vec![Location(1), Final]); "first or last")]
+    #[test_case("7,3,(5,3)", Lines(BTreeSet::from([3, 5, 7])); "line numbers")]
+    #[test_case("1,(2,3),!4", Set(vec![Location(1), Location(2), Location(3), Negate(Box::new(Location(4)))]); "line numbers and negation")]
     #[test_case("1,!$", Set(vec![Location(1), Negate(Box::new(Final))]); "first or last negated")]
     #[test_case("!(1,$)", Negate(Box::new(Set(vec![Location(1), Final]))); "negate set in brackets")]
+    #[test_case("# comment\n1 # comment\n, # comment\n$", Set(vec![Location(1), Final]); "comments in set")]
+    #[test_case("( # comment\n1 # comment\n) # comment", Location(1); "comments in brackets")]
+    #[test_case("1 # comment\n- # comment\n5", Between(address::Between::new(Location(1), Location(5))); "comments in range")]
     fn parse(input: &str, expected: Address) {
         let mut reader = StringReader::from(input);
-        let result = super::parse(&mut reader).unwrap();
+        let result = super::parse(&mut reader, &mut Default::default()).unwrap();
+        assert_eq!(result, expected)
+    }
+
+    #[test_case("field(3)/x/", FieldRegex(2, None, crate::Regex::from_str("x").unwrap()); "field")]
+    #[test_case("field( 1 -d: )/x/", FieldRegex(0, Some(':'), crate::Regex::from_str("x").unwrap()); "field with separator")]
+    #[test_case("/x/, field(2)//", FieldRegex(1, None, crate::Regex::from_str("x").unwrap()); "field reusing regex")]
+    fn parse_field(input: &str, expected: Address) {
+        let mut reader = StringReader::from(input);
+        let mut result = super::parse(&mut reader, &mut Default::default()).unwrap();
+        if let Set(mut set) = result {
+            result = set.remove(1);
+        }
         assert_eq!(result, expected)
     }
+
+    #[test_case("field(0)/x/"; "zero index")]
+    #[test_case("field()/x/"; "missing index")]
+    #[test_case("field(2) 5"; "not a regex")]
+    #[test_case("field(2)//"; "no previous regex")]
+    #[test_case("fields(2)/x/"; "wrong name")]
+    fn invalid_field(input: &str) {
+        let mut reader = StringReader::from(input);
+        assert!(super::parse(&mut reader, &mut Default::default()).is_err())
+    }
+
+    #[test_case("len==80", Length(Comparison::Equal, 80); "equal")]
+    #[test_case("len<10", Length(Comparison::Less, 10); "less")]
+    #[test_case("len>80", Length(Comparison::Greater, 80); "greater")]
+    #[test_case("len<=0", Length(Comparison::LessOrEqual, 0); "less or equal")]
+    #[test_case("len>= 5", Length(Comparison::GreaterOrEqual, 5); "greater or equal")]
+    fn parse_length(input: &str, expected: Address) {
+        let mut reader = StringReader::from(input);
+        let result = super::parse(&mut reader, &mut Default::default()).unwrap();
+        assert_eq!(result, expected)
+    }
+
+    #[test_case("len=5"; "single equal")]
+    #[test_case("len<"; "missing length")]
+    #[test_case("len<x"; "not a number")]
+    fn invalid_length(input: &str) {
+        let mut reader = StringReader::from(input);
+        assert!(super::parse(&mut reader, &mut Default::default()).is_err())
+    }
+
+    #[test_case("/a/,/b/", "(?:a)|(?:b)", "/a/, /b/"; "two regexes")]
+    #[test_case("/^a$/, /b/, (/c/)", "(?:^a$)|(?:b)|(?:c)", "/^a$/, /b/, /c/"; "anchors")]
+    #[test_case("/(?i)a/,/b/", "(?:(?i)a)|(?:b)", "/(?i)a/, /b/"; "flags")]
+    #[test_case("/(?:a)/,/b/", "(?:(?:a))|(?:b)", "/(?:a)/, /b/"; "non-capturing group")]
+    fn fused(input: &str, expected: &str, display: &str) {
+        let mut reader = StringReader::from(input);
+        let result = super::parse(&mut reader, &mut Default::default()).unwrap();
+        let Fused(regex, _) = &result else {
+            panic!("{result:?} is not fused")
+        };
+        assert_eq!(regex, &crate::Regex::from_str(expected).unwrap());
+        assert_eq!(result.to_string(), display);
+    }
+
+    #[test_case("/a/,!/b/"; "negated")]
+    #[test_case("/a/,5"; "mixed")]
+    #[test_case("/(?x) a # comment\n/,/b/"; "verbose")]
+    #[test_case("/(a)/,/b/"; "capture group")]
+    #[test_case("/(?<n>a)/,/(?<n>b)/"; "same group names")]
+    fn not_fused(input: &str) {
+        let mut reader = StringReader::from(input);
+        let result = super::parse(&mut reader, &mut Default::default()).unwrap();
+        assert!(matches!(result, Set(_)))
+    }
+
+    #[test]
+    fn fused_matches_like_set() {
+        let patterns = ["^a", "b$", "(?i)C", "x+y", "^$"];
+        let unfused = Set(patterns
+            .iter()
+            .map(|p| Regex(crate::Regex::from_str(p).unwrap()))
+            .collect());
+        let input = patterns
+            .iter()
+            .map(|p| format!("/{p}/"))
+            .collect::<Vec<_>>()
+            .join(",");
+        let fused = super::parse(
+            &mut StringReader::from(input.as_str()),
+            &mut Default::default(),
+        )
+        .unwrap();
+        assert!(matches!(fused, Fused(..)));
+
+        for (i, s) in ["abc", "cab", "zzz", "xc", "xxy", "", " a", "b a"]
+            .iter()
+            .enumerate()
+        {
+            let line = crate::Line(i + 1, s.to_string(), None);
+            assert_eq!(fused.matches(&line), unfused.matches(&line), "{s}");
+        }
+    }
 }
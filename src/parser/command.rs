@@ -1,75 +1,188 @@
 use super::{
     Error,
     reader::Reader,
-    utils::{parse_regex, read_integer, skip_line, skip_whitespace},
+    utils::{
+        RegexContext, parse_regex, read_identifier, read_integer, skip_blanks, skip_line, unescape,
+    },
 };
-use crate::command::Command::{self, *};
-use anyhow::{Result, anyhow, bail};
+use crate::command::{
+    Command::{self, *},
+    NUMBER_WIDTH,
+    Position::{self, *},
+    TAB_WIDTH,
+};
+use anyhow::{Result, bail};
 
-pub(crate) fn parse<R: Reader>(reader: &mut R) -> Result<Vec<Command>> {
+pub(crate) fn parse<R: Reader>(reader: &mut R, ctx: &mut RegexContext) -> Result<Vec<Command>> {
     let mut cmds = Vec::new();
     while let Some(c) = reader.next()? {
         let cmd = match c {
-            ';' => break,
+            ';' | '\n' => break,
             '.' => {
                 cmds.push(Break);
                 break;
             }
             'b' => {
-                skip_whitespace(reader);
-                reader.expect(';')?;
+                skip_blanks(reader);
+                if !reader.next_is('\n')? {
+                    reader.expect(';')?;
+                }
                 cmds.push(Break);
                 break;
             }
+            't' => {
+                expect_branch_end(reader, c)?;
+                BranchIfSub
+            }
+            'T' => {
+                expect_branch_end(reader, c)?;
+                BranchIfNoSub
+            }
             'p' => Println,
-            'P' => Print,
+            'P' => Print(!reader.next_is('-')?),
             '\\' => {
                 let s = read_escaped(reader)?;
-                Insert(s)
+                Insert(escape_template(&s))
             }
-            'l' => Escapeln,
-            's' => parse_substitute(reader)?,
+            'l' => {
+                skip_blanks(reader);
+                let s = read_integer(reader)?;
+                let width = if s.is_empty() { None } else { Some(s.parse()?) };
+                Escapeln(width)
+            }
+            's' => parse_substitute(reader, ctx)?,
             'k' => {
-                skip_whitespace(reader);
-                read_range(reader)?
+                let bytes = reader.next_is('b')?;
+                skip_blanks(reader);
+                read_range(reader, bytes)?
+            }
+            'c' => {
+                skip_blanks(reader);
+                let (indices, sep) = parse_fields(reader)?;
+                Field(indices, sep)
+            }
+            'S' => {
+                let c = if reader.next_is('/')? {
+                    let s = unescape(&read_until(reader, '/')?)?;
+                    let mut chars = s.chars();
+                    match (chars.next(), chars.next()) {
+                        (Some(c), None) => Some(c),
+                        _ => bail!("S expects a single character, got '{}'", s),
+                    }
+                } else {
+                    None
+                };
+                Squeeze(c)
+            }
+            'n' => {
+                skip_blanks(reader);
+                parse_number_prefix(reader)?
             }
-            '=' => LineNumber,
+            'E' => {
+                skip_blanks(reader);
+                let s = read_integer(reader)?;
+                let width = if s.is_empty() { TAB_WIDTH } else { s.parse()? };
+                if width == 0 {
+                    bail!("tab width needs to be >0");
+                }
+                Expand(width)
+            }
+            '~' => {
+                if reader.next_is('^')? {
+                    TrimStart
+                } else if reader.next_is('$')? {
+                    TrimEnd
+                } else {
+                    Trim
+                }
+            }
+            'v' => Reverse,
+            'U' => Upper,
+            'L' => Lower,
+            '=' => LineNumber(!reader.next_is('-')?),
+            'F' => PrintFilename,
             'd' => Delete,
+            'B' => SqueezeBlank,
+            'u' => {
+                skip_blanks(reader);
+                if reader.peek()?.is_some_and(|c| c.is_ascii_digit()) {
+                    let (indices, sep) = parse_fields(reader)?;
+                    Uniq(indices, sep)
+                } else {
+                    Uniq(Vec::new(), None)
+                }
+            }
             '&' => GetLine,
             'z' => Reset,
-            'h' => Hold,
-            'g' => Get,
-            'x' => Exchange,
-            'j' => Joinln,
+            'h' => Hold(read_register(reader)?),
+            'H' => HoldAppend,
+            'g' => Get(read_register(reader)?),
+            'x' => Exchange(read_register(reader)?),
+            'j' | 'G' => Joinln,
             'J' => Join,
-            'e' => Eval,
+            'e' => {
+                let ignore_status = reader.next_is('!')?;
+                let keep_newline = reader.next_is('+')?;
+                let command = match reader.peek()? {
+                    Some(c @ ('\'' | '"')) => {
+                        reader.skip();
+                        Some(read_until(reader, c)?)
+                    }
+                    _ => None,
+                };
+                Eval {
+                    command,
+                    ignore_status,
+                    keep_newline,
+                }
+            }
+            '@' => {
+                let name = read_identifier(reader)?;
+                if name.is_empty() {
+                    bail!("@ expects a callback name, like @name");
+                }
+                Call(name)
+            }
+            'w' => match reader.next()? {
+                Some(c @ ('\'' | '"')) => WriteFile(read_until(reader, c)?.into()),
+                _ => bail!("w expects a quoted file name, like w'file'"),
+            },
+            'r' if matches!(reader.peek()?, Some('\'' | '"')) => {
+                let c = reader.next()?.unwrap();
+                ReadFile(read_until(reader, c)?.into())
+            }
             'r' => {
-                skip_whitespace(reader);
+                skip_blanks(reader);
                 let s = read_integer(reader)?;
                 let num = if s.is_empty() { 1 } else { s.parse()? };
                 Readln(num)
             }
             'R' => ReadReplace,
-            'q' => {
-                skip_whitespace(reader);
+            'q' | 'Q' => {
+                skip_blanks(reader);
                 let s = read_integer(reader)?;
                 let code = if s.is_empty() { 0 } else { s.parse()? };
-                Quit(code)
+                if c == 'q' {
+                    Quit(code)
+                } else {
+                    QuitSilent(code)
+                }
             }
             '\'' | '"' => {
-                let msg = unescape(&read_until(reader, c)?)?;
+                let msg = unescape_template(&read_until(reader, c)?)?;
                 Insert(msg)
             }
             '#' => {
+                // the comment runs until the end of the line, so it ends the instruction
                 skip_line(reader);
-                continue;
+                break;
             }
             c if c.is_whitespace() => continue,
             _ => bail!(Error::Unexpected(c)),
         };
         cmds.push(cmd);
 
-        skip_whitespace(reader);
+        skip_blanks(reader);
         if let Some('}') = reader.peek()? {
             break;
         }
@@ -77,12 +190,36 @@ pub(crate) fn parse<R: Reader>(reader: &mut R) -> Result<Vec<Command>> {
     Ok(cmds)
 }
 
+/// There are no labels, the branches always jump to the end of the script, so the commands
+/// need to end the instruction, and a `sed` label following them is not read as the commands.
+fn expect_branch_end<R: Reader>(reader: &mut R, cmd: char) -> Result<()> {
+    skip_blanks(reader);
+    match reader.peek()? {
+        None | Some(';' | '\n' | '#') => Ok(()),
+        Some(_) => bail!(
+            "'{cmd}' needs to end the instruction, it does not take a label and always jumps to the end of the script"
+        ),
+    }
+}
+
 fn read_escaped<R: Reader>(reader: &mut R) -> Result<String> {
     let mut acc = "\\".to_string();
     let Some(c) = reader.next()? else {
         bail!(Error::EndOfInput)
     };
     match c {
+        'u' if reader.next_is('{')? => {
+            acc.push_str("u{");
+            loop {
+                match reader.next()? {
+                    Some('}') => break,
+                    Some(c) => acc.push(c),
+                    None => bail!(Error::Missing('}')),
+                }
+            }
+            acc.push('}');
+            unescape(&acc)
+        }
         'u' => {
             acc.push(c);
             for _ in 0..4 {
@@ -103,6 +240,17 @@ fn read_escaped<R: Reader>(reader: &mut R) -> Result<String> {
             }
             unescape(&acc)
         }
+        c if c.is_digit(8) => {
+            acc.push(c);
+            for _ in 0..2 {
+                match reader.peek()? {
+                    Some(c) if c.is_digit(8) => acc.push(c),
+                    _ => break,
+                }
+                reader.skip();
+            }
+            unescape(&acc)
+        }
         c => {
             acc.push(c);
             unescape(&acc).or(Ok(c.to_string()))
@@ -110,31 +258,164 @@ fn read_escaped<R: Reader>(reader: &mut R) -> Result<String> {
     }
 }
 
-fn parse_substitute<R: Reader>(reader: &mut R) -> Result<Command> {
+/// Read the optional `:name` of a hold register.
+fn read_register<R: Reader>(reader: &mut R) -> Result<Option<String>> {
+    if !reader.next_is(':')? {
+        return Ok(None);
+    }
+    let name = read_identifier(reader)?;
+    if name.is_empty() {
+        bail!("missing register name after ':'");
+    }
+    Ok(Some(name))
+}
+
+fn parse_substitute<R: Reader>(reader: &mut R, ctx: &mut RegexContext) -> Result<Command> {
     if reader.peek()? != Some('/') {
         bail!(Error::Missing('/'));
     }
 
-    // Parse: s/src/dst/[limit]
-    let Some(src) = parse_regex(reader)? else {
-        bail!("empty regular expression");
+    // Parse: s/src/dst/[limit][g][flags]
+    let Some(mut src) = parse_regex(reader, ctx)? else {
+        bail!("no previous regular expression");
+    };
+    let dst = read_template(reader, ctx.fixed_strings)?;
+    check_references(&src.0, &dst)?;
+
+    let mut limit = None;
+    let mut global = false;
+    let mut flags = String::new();
+    while let Some(c) = reader.peek()? {
+        match c {
+            'g' => {
+                if global {
+                    bail!("the substitution can have only one g flag");
+                }
+                reader.skip();
+                global = true;
+            }
+            c if c.is_ascii_digit() => {
+                if limit.is_some() {
+                    bail!("the substitution can have only one limit");
+                }
+                limit = Some(read_integer(reader)?.parse()?);
+            }
+            'i' | 'I' | 'm' | 'M' => {
+                reader.skip();
+                flags.push(c.to_ascii_lowercase());
+            }
+            _ => break,
+        }
+    }
+    if !flags.is_empty() {
+        // the inline flags are kept when the regex is compiled again with other options
+        src = format!("(?{flags}){}", src.0.as_str()).parse()?;
+    }
+
+    // g alone is the default, with the limit it replaces the matches starting from the limit
+    let limit = limit.unwrap_or(0);
+    Ok(Substitute {
+        regex: src,
+        replacement: dst,
+        limit,
+        global: global && limit > 0,
+    })
+}
+
+/// Check that the groups referred to in the substitution `template` exist in the `regex`,
+/// otherwise they would be silently replaced with empty strings. The references follow
+/// the syntax of [`regex::Regex::replace`].
+fn check_references(regex: &regex::Regex, template: &str) -> Result<()> {
+    let mut rest = template;
+    while let Some(pos) = rest.find('$') {
+        rest = &rest[pos + 1..];
+        if let Some(tail) = rest.strip_prefix('$') {
+            rest = tail;
+            continue;
+        }
+        let (name, tail) = match rest.strip_prefix('{').and_then(|s| s.split_once('}')) {
+            Some((name, tail)) => (name, tail),
+            None => {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                rest.split_at(end)
+            }
+        };
+        rest = tail;
+        if name.is_empty() {
+            // a lone `$` is a literal dollar
+            continue;
+        }
+        let exists = match name.parse::<usize>() {
+            Ok(idx) => idx < regex.captures_len(),
+            Err(_) => regex.capture_names().flatten().any(|n| n == name),
+        };
+        if !exists {
+            bail!(
+                "the substitution refers to the group '{}' missing in /{}/",
+                name,
+                regex
+            );
+        }
+    }
+    Ok(())
+}
+
+fn parse_number_prefix<R: Reader>(reader: &mut R) -> Result<Command> {
+    // Parse: n [width] [-s'sep']
+    let s = read_integer(reader)?;
+    let width = if s.is_empty() {
+        NUMBER_WIDTH
+    } else {
+        s.parse()?
+    };
+    skip_blanks(reader);
+    let sep = if reader.next_is('-')? {
+        reader.expect('s')?;
+        match reader.next()? {
+            Some(c @ ('\'' | '"')) => unescape(&read_until(reader, c)?)?,
+            _ => bail!("n expects a quoted separator, like n -s': '"),
+        }
+    } else {
+        "\t".to_string()
     };
-    let dst = read_template(reader)?;
+    Ok(NumberPrefix(width, sep))
+}
 
-    let mut limit = 0;
-    if let Some(c) = reader.peek()? {
-        if c == 'g' {
-            reader.skip();
-            // g is default, no need to update the limit
-        } else if c.is_ascii_digit() {
-            limit = read_integer(reader)?.parse()?;
+fn parse_fields<R: Reader>(reader: &mut R) -> Result<(Vec<usize>, Option<char>)> {
+    // Parse: N,M,... [-dC]
+    let mut indices = Vec::new();
+    loop {
+        let s = read_integer(reader)?;
+        if s.is_empty() {
+            bail!("missing field index");
+        }
+        let idx: usize = s.parse()?;
+        if idx == 0 {
+            bail!("field indexes need to be >0");
+        }
+        indices.push(idx - 1);
+        if !reader.next_is(',')? {
+            break;
         }
     }
 
-    Ok(Substitute(src, dst, limit))
+    skip_blanks(reader);
+    let sep = if reader.next_is('-')? {
+        reader.expect('d')?;
+        let Some(c) = reader.next()? else {
+            bail!(Error::EndOfInput)
+        };
+        Some(c)
+    } else {
+        None
+    };
+    Ok((indices, sep))
 }
 
-fn read_template<R: Reader>(reader: &mut R) -> Result<String> {
+/// Read the replacement, when `fixed` the `$` characters are always literal.
+fn read_template<R: Reader>(reader: &mut R, fixed: bool) -> Result<String> {
     let delim = '/';
     let mut acc = String::new();
     while let Some(c) = reader.peek()? {
@@ -143,16 +424,37 @@ fn read_template<R: Reader>(reader: &mut R) -> Result<String> {
                 reader.skip();
                 return unescape(&acc);
             }
-            c if c.is_ascii_digit() => {
-                // replace $N with ${N}
-                // "$123something" string is interpreted as "${123}something" rather than "${123something}"
-                acc.push('{');
-                acc.push_str(&read_integer(reader)?);
-                acc.push('}');
+            '$' if fixed => {
+                reader.skip();
+                acc.push_str("$$");
+            }
+            '$' => {
+                reader.skip();
+                match reader.peek()? {
+                    Some('$') => {
+                        reader.skip();
+                        acc.push_str("$$");
+                    }
+                    Some(c) if c.is_ascii_digit() => {
+                        // replace $N with ${N}
+                        // "$123something" string is interpreted as "${123}something" rather than "${123something}"
+                        acc.push_str("${");
+                        acc.push_str(&read_integer(reader)?);
+                        acc.push('}');
+                    }
+                    // named groups: $name or ${name}
+                    Some(c) if c == '{' || c == '_' || c.is_alphanumeric() => acc.push('$'),
+                    // not a group reference, so it is a literal dollar
+                    _ => acc.push_str("$$"),
+                }
             }
             '\\' => {
                 reader.skip();
                 if let Some(e) = reader.next()? {
+                    if e == '$' {
+                        acc.push_str("$$");
+                        continue;
+                    }
                     if e != delim {
                         acc.push(c);
                     }
@@ -170,39 +472,63 @@ fn read_template<R: Reader>(reader: &mut R) -> Result<String> {
     bail!(Error::Missing(delim))
 }
 
-fn read_range<R: Reader>(reader: &mut R) -> Result<Command> {
-    let s = read_integer(reader)?;
-    let lhs = if s.is_empty() {
-        0
-    } else {
-        let lhs: usize = s.parse()?;
-        if lhs == 0 {
-            bail!("character indexes need to be >0");
+fn read_range<R: Reader>(reader: &mut R, bytes: bool) -> Result<Command> {
+    // `-N-` is the N-th character from the end, while `-M` is the same as `1-M`
+    let lhs = if reader.next_is('-')? {
+        let s = read_integer(reader)?;
+        if s.is_empty() {
+            // the dash was the range separator, as in `-` or `--M`
+            return Ok(Keep(Start(0), read_end(reader)?, bytes));
         }
-        lhs - 1
+        let idx = read_index(&s)?;
+        if !reader.next_is('-')? {
+            return Ok(Keep(Start(0), Some(Start(idx)), bytes));
+        }
+        End(idx)
+    } else {
+        let s = read_integer(reader)?;
+        let lhs = if s.is_empty() { 0 } else { read_index(&s)? - 1 };
+        if !reader.next_is('-')? {
+            return Ok(Keep(Start(lhs), Some(Start(lhs + 1)), bytes));
+        };
+        Start(lhs)
     };
 
-    if !reader.next_is('-')? {
-        return Ok(Keep(lhs, Some(1)));
-    };
+    let rhs = read_end(reader)?;
+    if let (Start(lo), Some(Start(hi))) = (lhs, rhs)
+        && lo >= hi
+    {
+        bail!(
+            "invalid character index range: {} > {} in {}-{}",
+            lo + 1,
+            hi,
+            lo + 1,
+            hi,
+        );
+    }
+    Ok(Keep(lhs, rhs, bytes))
+}
 
+/// Read the optional upper bound of the range, `-M` is counted from the end.
+fn read_end<R: Reader>(reader: &mut R) -> Result<Option<Position>> {
+    let from_end = reader.next_is('-')?;
     let s = read_integer(reader)?;
-    let rhs = if s.is_empty() {
-        None
-    } else {
-        let rhs: usize = s.parse()?;
-        if rhs == 0 || lhs > rhs {
-            bail!(
-                "invalid character index range: {} > {} in {}-{}",
-                lhs + 1,
-                rhs,
-                lhs + 1,
-                rhs,
-            );
+    if s.is_empty() {
+        if from_end {
+            bail!("missing character index after '-'");
         }
-        Some(rhs - lhs)
-    };
-    Ok(Keep(lhs, rhs))
+        return Ok(None);
+    }
+    let idx = read_index(&s)?;
+    Ok(Some(if from_end { End(idx) } else { Start(idx) }))
+}
+
+fn read_index(s: &str) -> Result<usize> {
+    let idx: usize = s.parse()?;
+    if idx == 0 {
+        bail!("character indexes need to be >0");
+    }
+    Ok(idx)
 }
 
 fn read_until<R: Reader>(reader: &mut R, delim: char) -> Result<String> {
@@ -226,6 +552,36 @@ fn read_until<R: Reader>(reader: &mut R, delim: char) -> Result<String> {
     bail!(Error::Missing(delim))
 }
 
-fn unescape(s: &str) -> Result<String> {
-    unescape::unescape(s).ok_or(anyhow!("unrecognized escape characters in '{}'", s))
+/// Unescape the string, but keep `\\` and `\$` escaped, so that the literal backslashes
+/// and dollars can be told apart from the `$` placeholders when printing the string.
+fn unescape_template(s: &str) -> Result<String> {
+    let mut acc = String::with_capacity(s.len());
+    let mut segment = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            segment.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(e @ ('\\' | '$')) => {
+                acc.push_str(&unescape(&segment)?.replace('\\', "\\\\"));
+                segment.clear();
+                acc.push(c);
+                acc.push(e);
+            }
+            Some(e) => {
+                segment.push(c);
+                segment.push(e);
+            }
+            None => segment.push(c),
+        }
+    }
+    acc.push_str(&unescape(&segment)?.replace('\\', "\\\\"));
+    Ok(acc)
+}
+
+/// Escape the backslashes and dollars, so that the string is printed as-is.
+fn escape_template(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('$', "\\$")
 }
@@ -1,4 +1,4 @@
-use super::{address, command, reader::Reader, utils};
+use super::{address, command, reader::Reader, utils, utils::RegexContext};
 use crate::{Action, address::Address, command::Command};
 use anyhow::{Result, bail};
 
@@ -6,12 +6,13 @@ pub(crate) fn parse_instruction<R: Reader>(
     reader: &mut R,
     actions: &mut Vec<Action>,
     finally: &mut Vec<Command>,
+    ctx: &mut RegexContext,
 ) -> Result<()> {
     // [address][commands]
     utils::skip_whitespace(reader);
-    let mut address = address::parse(reader)?;
+    let mut address = address::parse(reader, ctx)?;
     utils::skip_whitespace(reader);
-    let commands = command::parse(reader)?;
+    let commands = command::parse(reader, ctx)?;
 
     if address == Address::Final {
         for cmd in commands.into_iter() {
@@ -31,7 +32,7 @@ impl Address {
     fn replace_maybe(&mut self, subst: Option<&Command>) -> Result<()> {
         match self {
             Address::Maybe => {
-                let Some(Command::Substitute(regex, _, _)) = subst else {
+                let Some(Command::Substitute { regex, .. }) = subst else {
                     bail!("{} must be followed by a substitution", self)
                 };
                 *self = Address::Regex(regex.clone());
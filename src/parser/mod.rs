@@ -6,10 +6,12 @@ mod reader;
 mod regex_reader;
 mod utils;
 
+pub use program::Script;
 #[cfg(test)]
 pub(crate) use reader::StringReader;
+pub use utils::unescape;
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Error {
     Missing(char),
     Unexpected(char),
@@ -26,3 +28,21 @@ impl std::fmt::Display for Error {
         }
     }
 }
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::Error;
+    use crate::Program;
+    use std::str::FromStr;
+    use test_case::test_case;
+
+    #[test_case("p'abc", Error::Missing('\''); "missing")]
+    #[test_case("p %", Error::Unexpected('%'); "unexpected")]
+    #[test_case("c1 -d", Error::EndOfInput; "end of input")]
+    fn downcast(input: &str, expected: Error) {
+        let err = Program::from_str(input).unwrap_err();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&expected));
+    }
+}
@@ -1,19 +1,26 @@
 use super::{
     instruction::parse_instruction,
     reader::{FileReader, Reader, StringReader},
-    utils::skip_whitespace,
+    utils::{RegexContext, skip_separators},
 };
 use crate::{Action, command::Command, program::Program};
-use anyhow::Result;
-use std::str::FromStr;
+use anyhow::{Context, Result, bail};
+use std::{path::PathBuf, str::FromStr};
+
+/// Source of a part of the script, see [`Program::parse_scripts_with_data`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Script {
+    /// The script given as a string
+    Text(String),
+    /// The script read from the file (`-` for stdin)
+    File(PathBuf),
+}
 
 impl TryFrom<&std::path::PathBuf> for Program {
     type Error = anyhow::Error;
 
     fn try_from(value: &std::path::PathBuf) -> Result<Self, Self::Error> {
-        let reader = &mut FileReader::try_from(value)?;
-        let (actions, finally) = parse(reader)?;
-        Ok(Program::new(actions, finally))
+        Program::parse_file(value, false)
     }
 }
 
@@ -21,20 +28,118 @@ impl FromStr for Program {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let reader = &mut StringReader::from(s);
-        let (actions, finally) = parse(reader)?;
+        Program::parse(s, false)
+    }
+}
+
+impl Program {
+    /// Parse the script, with `fixed_strings` the regular expressions and the replacements
+    /// of the substitutions are literal strings, like with `grep -F`.
+    ///
+    /// ```
+    /// use se::Program;
+    ///
+    /// let mut program = Program::parse("/a.b/ s/.b/$1/ p", true).unwrap();
+    /// let (output, _) = program.run_str("axb\na.b", false).unwrap();
+    /// assert_eq!(output, "a$1\n");
+    /// ```
+    pub fn parse(script: &str, fixed_strings: bool) -> Result<Program> {
+        let reader = &mut StringReader::from(script);
+        let (actions, finally) = parse(reader, fixed_strings)?;
         Ok(Program::new(actions, finally))
     }
+
+    /// Same as [`Program::parse`], but the script is read from the file (`-` for stdin).
+    /// The script ends at the `__END__` line, if there is one.
+    pub fn parse_file(path: &std::path::PathBuf, fixed_strings: bool) -> Result<Program> {
+        let (program, _) = Program::parse_file_with_data(path, fixed_strings)?;
+        Ok(program)
+    }
+
+    /// Same as [`Program::parse_file`], but also return the content of the file following
+    /// the `__END__` line, which can be used as the inline input data.
+    pub fn parse_file_with_data(
+        path: &PathBuf,
+        fixed_strings: bool,
+    ) -> Result<(Program, Option<Vec<u8>>)> {
+        Program::parse_files_with_data(std::slice::from_ref(path), fixed_strings)
+    }
+
+    /// Same as [`Program::parse_file_with_data`], but the scripts are read from all the files
+    /// in order, as if they were a single script, so e.g. an empty regular expression can refer
+    /// to the one from the previous file. Only the last file can have the inline data.
+    pub fn parse_files_with_data(
+        paths: &[PathBuf],
+        fixed_strings: bool,
+    ) -> Result<(Program, Option<Vec<u8>>)> {
+        let scripts: Vec<_> = paths.iter().cloned().map(Script::File).collect();
+        Program::parse_scripts_with_data(&scripts, fixed_strings)
+    }
+
+    /// Same as [`Program::parse_files_with_data`], but the parts of the script can be given
+    /// both as the strings and the files.
+    pub fn parse_scripts_with_data(
+        scripts: &[Script],
+        fixed_strings: bool,
+    ) -> Result<(Program, Option<Vec<u8>>)> {
+        let mut actions = Vec::new();
+        let mut finally = Vec::new();
+        let mut ctx = RegexContext {
+            last: None,
+            fixed_strings,
+        };
+        let mut data = None;
+        for (idx, script) in scripts.iter().enumerate() {
+            let path = match script {
+                Script::Text(script) => {
+                    let reader = &mut StringReader::from(script.as_str());
+                    parse_into(reader, &mut actions, &mut finally, &mut ctx)?;
+                    continue;
+                }
+                Script::File(path) => path,
+            };
+            let mut reader = FileReader::try_from(path)
+                .with_context(|| format!("cannot read {}", path.display()))?;
+            parse_into(&mut reader, &mut actions, &mut finally, &mut ctx).with_context(|| {
+                format!("error in {} at line {}", path.display(), reader.line())
+            })?;
+            let line = reader.line();
+            data = reader.into_data()?;
+            if data.is_some() && idx + 1 < scripts.len() {
+                bail!(
+                    "error in {} at line {}: only the last script can be followed by the __END__ data",
+                    path.display(),
+                    line
+                );
+            }
+        }
+        Ok((Program::new(actions, finally), data))
+    }
 }
 
-fn parse<R: Reader>(reader: &mut R) -> Result<(Vec<Action>, Vec<Command>)> {
+fn parse<R: Reader>(reader: &mut R, fixed_strings: bool) -> Result<(Vec<Action>, Vec<Command>)> {
     let mut actions = Vec::new();
     let mut finally = Vec::new();
+    let mut ctx = RegexContext {
+        last: None,
+        fixed_strings,
+    };
+    parse_into(reader, &mut actions, &mut finally, &mut ctx)?;
+    Ok((actions, finally))
+}
+
+fn parse_into<R: Reader>(
+    reader: &mut R,
+    actions: &mut Vec<Action>,
+    finally: &mut Vec<Command>,
+    ctx: &mut RegexContext,
+) -> Result<()> {
+    skip_separators(reader)?;
     while reader.peek()?.is_some() {
-        parse_instruction(reader, &mut actions, &mut finally)?;
-        skip_whitespace(reader);
+        parse_instruction(reader, actions, finally, ctx)?;
+        skip_separators(reader)?;
     }
-    Ok((actions, finally))
+    Ok(())
 }
 
 #[cfg(test)]
@@ -44,8 +149,9 @@ mod tests {
         Action,
         address::{self, Address::*},
         command::Command::*,
+        lines::TempFile,
     };
-    use std::str::FromStr;
+    use std::{collections::BTreeSet, str::FromStr};
     use test_case::test_case;
 
     #[test_case("", Program::from(Vec::new()); "empty")]
@@ -53,18 +159,50 @@ mod tests {
         Action::Condition(Always, 1),
         Action::Command(Println),
     ]); "print all")]
+    #[test_case("#!/usr/bin/env -S se -f\np", Program::from(vec![
+        Action::Condition(Always, 1),
+        Action::Command(Println),
+    ]); "shebang")]
+    #[test_case("# comment\n1p # another comment\n", Program::from(vec![
+        Action::Condition(Location(1), 1),
+        Action::Command(Println),
+    ]); "comments")]
+    #[test_case("p # comment ; d\n= # comment\n# comment\n; $ # comment\np", Program::new(
+        vec![
+            Action::Condition(Always, 1),
+            Action::Command(Println),
+            Action::Condition(Always, 1),
+            Action::Command(LineNumber(true)),
+        ],
+        vec![Println],
+    ); "comments after commands")]
+    #[test_case("'#' s/#/x/ # comment", Program::from(vec![
+        Action::Condition(Always, 2),
+        Action::Command(Insert("#".to_string())),
+        Action::Command(Substitute {
+            regex: crate::Regex::from_str("#").unwrap(),
+            replacement: "x".to_string(),
+            limit: 0,
+            global: false,
+        }),
+    ]); "hash in string and regex")]
     #[test_case(r"='\n'p", Program::from(vec![
         Action::Condition(Always, 3),
-        Action::Command(LineNumber),
+        Action::Command(LineNumber(true)),
         Action::Command(Insert("\n".to_string())),
         Action::Command(Println),
     ]); "print with newlines")]
     #[test_case(r"   = '\n'  p  ", Program::from(vec![
         Action::Condition(Always, 3),
-        Action::Command(LineNumber),
+        Action::Command(LineNumber(true)),
         Action::Command(Insert("\n".to_string())),
         Action::Command(Println),
     ]); "commands with spaces")]
+    #[test_case("=- =", Program::from(vec![
+        Action::Condition(Always, 2),
+        Action::Command(LineNumber(false)),
+        Action::Command(LineNumber(true)),
+    ]); "line number without newline")]
     #[test_case("-", Program::from(vec![
         Action::Condition(Between(address::Between::new(Location(1), Final)), 0),
     ]); "infinite range")]
@@ -90,13 +228,13 @@ mod tests {
         Action::Condition(Regex(crate::Regex::from_str("abc/").unwrap()), 0)
     ]); "regex match with escape")]
     #[test_case("^abc$", Program::from(vec![
-        Action::Condition(Regex(crate::Regex::from_str("^abc$").unwrap()), 0)
+        Action::Condition(Regex(crate::Regex::from_str("(?-m)^(?:abc)$").unwrap()), 0)
     ]); "whole line regex match")]
     #[test_case(r"^\$abc$", Program::from(vec![
-        Action::Condition(Regex(crate::Regex::from_str(r"^\$abc$").unwrap()), 0)
+        Action::Condition(Regex(crate::Regex::from_str(r"(?-m)^(?:\$abc)$").unwrap()), 0)
     ]); "whole line regex match with escape")]
     #[test_case(r"^\$$", Program::from(vec![
-        Action::Condition(Regex(crate::Regex::from_str(r"^\$$").unwrap()), 0)
+        Action::Condition(Regex(crate::Regex::from_str(r"(?-m)^(?:\$)$").unwrap()), 0)
     ]); "whole line only dollar")]
     #[test_case("/abc/-/def/", Program::from(vec![
         Action::Condition(Between(address::Between::new(
@@ -111,13 +249,13 @@ mod tests {
         Action::Condition(Negate(Box::new(Location(666))), 0)
     ]); "negation with space")]
     #[test_case("5,6,10", Program::from(vec![
-        Action::Condition(Set(vec![Location(5), Location(6), Location(10)]), 0),
+        Action::Condition(Lines(BTreeSet::from([5, 6, 10])), 0),
     ]); "set")]
     #[test_case("((5),((6),10))", Program::from(vec![
-        Action::Condition(Set(vec![Location(5), Location(6), Location(10)]), 0),
+        Action::Condition(Lines(BTreeSet::from([5, 6, 10])), 0),
     ]); "set with brackets")]
     #[test_case("  5, 6  ,10   ", Program::from(vec![
-        Action::Condition(Set(vec![Location(5), Location(6), Location(10)]), 0),
+        Action::Condition(Lines(BTreeSet::from([5, 6, 10])), 0),
     ]); "set with spaces")]
     #[test_case("5,6,!10", Program::from(vec![
         Action::Condition(Set(vec![Location(5), Location(6), Negate(Box::new(Location(10)))]), 0),
@@ -130,44 +268,153 @@ mod tests {
     ]); "regex")]
     #[test_case(r"s/abc/def/", Program::from(vec![
         Action::Condition(Always, 1),
-        Action::Command(Substitute(
-                crate::Regex::from_str("abc").unwrap(),
-                "def".to_string(),
-                0,
-            )),
+        Action::Command(Substitute {
+            regex: crate::Regex::from_str("abc").unwrap(),
+            replacement: "def".to_string(),
+            limit: 0,
+            global: false,
+        }),
     ]); "substitute")]
     #[test_case(r"s/abc/def/5", Program::from(vec![
         Action::Condition(Always, 1),
-        Action::Command(Substitute(
-                crate::Regex::from_str("abc").unwrap(),
-                "def".to_string(),
-                5,
-            )),
+        Action::Command(Substitute {
+            regex: crate::Regex::from_str("abc").unwrap(),
+            replacement: "def".to_string(),
+            limit: 5,
+            global: false,
+        }),
     ]); "substitute with count")]
     #[test_case(r"s/abc/def/g", Program::from(vec![
         Action::Condition(Always, 1),
-        Action::Command(Substitute(
-                crate::Regex::from_str("abc").unwrap(),
-                "def".to_string(),
-                0,
-            )),
+        Action::Command(Substitute {
+            regex: crate::Regex::from_str("abc").unwrap(),
+            replacement: "def".to_string(),
+            limit: 0,
+            global: false,
+        }),
     ]); "substitute with global count")]
     #[test_case(r"/abc/s/def/ghi/g", Program::from(vec![
         Action::Condition(Regex(crate::Regex::from_str("abc").unwrap()), 1),
-        Action::Command(Substitute(
-                crate::Regex::from_str("def").unwrap(),
-                "ghi".to_string(),
-                0,
-            )),
+        Action::Command(Substitute {
+            regex: crate::Regex::from_str("def").unwrap(),
+            replacement: "ghi".to_string(),
+            limit: 0,
+            global: false,
+        }),
     ]); "condense match and substitute")]
-    #[test_case(r"s/(abc)/__$123__/", Program::from(vec![
+    #[test_case(r"/foo/s//bar/", Program::from(vec![
+        Action::Condition(Regex(crate::Regex::from_str("foo").unwrap()), 1),
+        Action::Command(Substitute {
+            regex: crate::Regex::from_str("foo").unwrap(),
+            replacement: "bar".to_string(),
+            limit: 0,
+            global: false,
+        }),
+    ]); "reuse regex in substitute")]
+    #[test_case(r"s/foo/bar/; //d", Program::from(vec![
         Action::Condition(Always, 1),
-        Action::Command(Substitute(
-                crate::Regex::from_str("(abc)").unwrap(),
-                "__${123}__".to_string(),
-                0,
-            )),
+        Action::Command(Substitute {
+            regex: crate::Regex::from_str("foo").unwrap(),
+            replacement: "bar".to_string(),
+            limit: 0,
+            global: false,
+        }),
+        Action::Condition(Regex(crate::Regex::from_str("foo").unwrap()), 1),
+        Action::Command(Delete),
+    ]); "reuse regex in address")]
+    #[test_case(r"s/(a)(b)(c)(d)(e)(f)(g)(h)(i)(j)(k)(l)/__$12__/", Program::from(vec![
+        Action::Condition(Always, 1),
+        Action::Command(Substitute {
+            regex: crate::Regex::from_str("(a)(b)(c)(d)(e)(f)(g)(h)(i)(j)(k)(l)").unwrap(),
+            replacement: "__${12}__".to_string(),
+            limit: 0,
+            global: false,
+        }),
     ]); "substitute with numbered group")]
+    #[test_case(r"s/x/$$5/", Program::from(vec![
+        Action::Condition(Always, 1),
+        Action::Command(Substitute {
+            regex: crate::Regex::from_str("x").unwrap(),
+            replacement: "$$5".to_string(),
+            limit: 0,
+            global: false,
+        }),
+    ]); "literal dollar")]
+    #[test_case(r"s/x/\$5/", Program::from(vec![
+        Action::Condition(Always, 1),
+        Action::Command(Substitute {
+            regex: crate::Regex::from_str("x").unwrap(),
+            replacement: "$$5".to_string(),
+            limit: 0,
+            global: false,
+        }),
+    ]); "escaped dollar")]
+    #[test_case(r"s/x/a5$/", Program::from(vec![
+        Action::Condition(Always, 1),
+        Action::Command(Substitute {
+            regex: crate::Regex::from_str("x").unwrap(),
+            replacement: "a5$$".to_string(),
+            limit: 0,
+            global: false,
+        }),
+    ]); "dollar at the end")]
+    #[test_case(r"s/(?<name>x)/$name ${1}/", Program::from(vec![
+        Action::Condition(Always, 1),
+        Action::Command(Substitute {
+            regex: crate::Regex::from_str("(?<name>x)").unwrap(),
+            replacement: "$name ${1}".to_string(),
+            limit: 0,
+            global: false,
+        }),
+    ]); "named groups")]
+    #[test_case(r"s/x/y/m s/x/y/2Mi s/x/y/gmI", Program::from(vec![
+        Action::Condition(Always, 3),
+        Action::Command(Substitute {
+            regex: crate::Regex::from_str("(?m)x").unwrap(),
+            replacement: "y".to_string(),
+            limit: 0,
+            global: false,
+        }),
+        Action::Command(Substitute {
+            regex: crate::Regex::from_str("(?mi)x").unwrap(),
+            replacement: "y".to_string(),
+            limit: 2,
+            global: false,
+        }),
+        Action::Command(Substitute {
+            regex: crate::Regex::from_str("(?mi)x").unwrap(),
+            replacement: "y".to_string(),
+            limit: 0,
+            global: false,
+        }),
+    ]); "substitute with flags")]
+    #[test_case(r"s/x/y/2g s/x/y/g3 s/x/y/0g s/x/y/g", Program::from(vec![
+        Action::Condition(Always, 4),
+        Action::Command(Substitute {
+            regex: crate::Regex::from_str("x").unwrap(),
+            replacement: "y".to_string(),
+            limit: 2,
+            global: true,
+        }),
+        Action::Command(Substitute {
+            regex: crate::Regex::from_str("x").unwrap(),
+            replacement: "y".to_string(),
+            limit: 3,
+            global: true,
+        }),
+        Action::Command(Substitute {
+            regex: crate::Regex::from_str("x").unwrap(),
+            replacement: "y".to_string(),
+            limit: 0,
+            global: false,
+        }),
+        Action::Command(Substitute {
+            regex: crate::Regex::from_str("x").unwrap(),
+            replacement: "y".to_string(),
+            limit: 0,
+            global: false,
+        }),
+    ]); "substitute from the nth match")]
     #[test_case(r"1d;3d;7d", Program::from(vec![
         Action::Condition(Location(1), 1),
         Action::Command(Delete),
@@ -178,11 +425,12 @@ mod tests {
     ]); "multiple instructions")]
     #[test_case(r"?s/abc/def/5", Program::from(vec![
         Action::Condition(Regex(crate::Regex::from_str("abc").unwrap()), 1),
-        Action::Command(Substitute(
-                crate::Regex::from_str("abc").unwrap(),
-                "def".to_string(),
-                5,
-            )),
+        Action::Command(Substitute {
+            regex: crate::Regex::from_str("abc").unwrap(),
+            replacement: "def".to_string(),
+            limit: 5,
+            global: false,
+        }),
     ]); "maybe")]
     #[test_case(r"1-?s/abc/def/5", Program::from(vec![
         Action::Condition(
@@ -192,11 +440,12 @@ mod tests {
             )),
             1,
         ),
-        Action::Command(Substitute(
-                crate::Regex::from_str("abc").unwrap(),
-                "def".to_string(),
-                5,
-            )),
+        Action::Command(Substitute {
+            regex: crate::Regex::from_str("abc").unwrap(),
+            replacement: "def".to_string(),
+            limit: 5,
+            global: false,
+        }),
     ]); "maybe in range")]
     #[test_case(r"1,?s/abc/def/5", Program::from(vec![
         Action::Condition(
@@ -206,14 +455,242 @@ mod tests {
             ]),
             1,
         ),
-        Action::Command(Substitute(
-                crate::Regex::from_str("abc").unwrap(),
-                "def".to_string(),
-                5,
-            )),
+        Action::Command(Substitute {
+            regex: crate::Regex::from_str("abc").unwrap(),
+            replacement: "def".to_string(),
+            limit: 5,
+            global: false,
+        }),
     ]); "maybe in set")]
+    #[test_case("h H j G", Program::from(vec![
+        Action::Condition(Always, 4),
+        Action::Command(Hold(None)),
+        Action::Command(HoldAppend),
+        Action::Command(Joinln),
+        Action::Command(Joinln),
+    ]); "hold and join")]
+    #[test_case("h:a g:b_1 x:c xp", Program::from(vec![
+        Action::Condition(Always, 5),
+        Action::Command(Hold(Some("a".to_string()))),
+        Action::Command(Get(Some("b_1".to_string()))),
+        Action::Command(Exchange(Some("c".to_string()))),
+        Action::Command(Exchange(None)),
+        Action::Command(Println),
+    ]); "named registers")]
+    #[test_case(r"S S/-/ S/\t/", Program::from(vec![
+        Action::Condition(Always, 3),
+        Action::Command(Squeeze(None)),
+        Action::Command(Squeeze(Some('-'))),
+        Action::Command(Squeeze(Some('\t'))),
+    ]); "squeeze")]
+    #[test_case("E E4 E 2", Program::from(vec![
+        Action::Condition(Always, 3),
+        Action::Command(Expand(8)),
+        Action::Command(Expand(4)),
+        Action::Command(Expand(2)),
+    ]); "expand")]
+    #[test_case(r#"n n3 n 2 -s': ' n-s"\t|""#, Program::from(vec![
+        Action::Condition(Always, 4),
+        Action::Command(NumberPrefix(6, "\t".to_string())),
+        Action::Command(NumberPrefix(3, "\t".to_string())),
+        Action::Command(NumberPrefix(2, ": ".to_string())),
+        Action::Command(NumberPrefix(6, "\t|".to_string())),
+    ]); "number prefix")]
+    #[test_case("c3 c 2,4 -d: p", Program::from(vec![
+        Action::Condition(Always, 3),
+        Action::Command(Field(vec![2], None)),
+        Action::Command(Field(vec![1, 3], Some(':'))),
+        Action::Command(Println),
+    ]); "fields")]
+    #[test_case("u u2 u 1,3 -d: p", Program::from(vec![
+        Action::Condition(Always, 4),
+        Action::Command(Uniq(vec![], None)),
+        Action::Command(Uniq(vec![1], None)),
+        Action::Command(Uniq(vec![0, 2], Some(':'))),
+        Action::Command(Println),
+    ]); "uniq")]
+    #[test_case(r#"e e! e'cat' e!"cat" e+ e!+'cat' p"#, Program::from(vec![
+        Action::Condition(Always, 7),
+        Action::Command(Eval { command: None, ignore_status: false, keep_newline: false }),
+        Action::Command(Eval { command: None, ignore_status: true, keep_newline: false }),
+        Action::Command(Eval { command: Some("cat".to_string()), ignore_status: false, keep_newline: false }),
+        Action::Command(Eval { command: Some("cat".to_string()), ignore_status: true, keep_newline: false }),
+        Action::Command(Eval { command: None, ignore_status: false, keep_newline: true }),
+        Action::Command(Eval { command: Some("cat".to_string()), ignore_status: true, keep_newline: true }),
+        Action::Command(Println),
+    ]); "eval")]
+    #[test_case("@upper @x_1 p", Program::from(vec![
+        Action::Condition(Always, 3),
+        Action::Command(Call("upper".to_string())),
+        Action::Command(Call("x_1".to_string())),
+        Action::Command(Println),
+    ]); "callbacks")]
+    #[test_case(r"'$HOME \$HOME \\$'", Program::from(vec![
+        Action::Condition(Always, 1),
+        Action::Command(Insert(r"$HOME \$HOME \\$".to_string())),
+    ]); "escaped dollars")]
+    #[test_case(r#"w'out.txt' w"/dev/stdout""#, Program::from(vec![
+        Action::Condition(Always, 2),
+        Action::Command(WriteFile("out.txt".into())),
+        Action::Command(WriteFile("/dev/stdout".into())),
+    ]); "write file")]
     fn parse(input: &str, expected: Program) {
         let result = Program::from_str(input).unwrap();
         assert_eq!(result, expected)
     }
+
+    #[test_case("/a/ p ; 2-3 s/a/b/ l 5 ; $ =", "/a/ p\n2-3 s/a/b/ l 5\n$ =\n"; "instructions")]
+    #[test_case("p ; ; = b ; d", "\np\n\n= b\nd"; "empty instructions and break")]
+    #[test_case("1, /a/ p", "1,\n/a/\n  p"; "address and commands in separate lines")]
+    fn separators(semicolons: &str, newlines: &str) {
+        assert_eq!(
+            Program::from_str(semicolons).unwrap(),
+            Program::from_str(newlines).unwrap()
+        )
+    }
+
+    #[test_case("/a.b/ p", "a.b\n"; "regex")]
+    #[test_case("^a.b$ p", "a.b\n"; "whole line")]
+    #[test_case("/a.b/,/(x)/ p", "a.b\n(x)\n"; "fused")]
+    #[test_case(r"/\d/ p", "\\d\n"; "backslash")]
+    #[test_case(r"/a\/b/ p", "a/b\n"; "escaped slash")]
+    #[test_case("?s/.+/$1 $$ ${x}/ p", "a$1 $$ ${x}b\n"; "substitution")]
+    #[test_case("/a.b/ s//[$0]/ p", "[$0]\n"; "previous regex")]
+    fn fixed_strings(script: &str, expected: &str) {
+        let mut prog = Program::parse(script, true).unwrap();
+        let input = "a.b\naxb\n(x)\n\\d\n1\na/b\na.+b";
+        let (output, _) = prog.run_str(input, false).unwrap();
+        assert_eq!(output, expected);
+    }
+
+    #[test_case("s/a/$1/"; "no groups")]
+    #[test_case("s/(a)(b)/$3/"; "out of range")]
+    #[test_case("s/(?<x>a)/${y}/"; "missing name")]
+    #[test_case("/(a)/ s//$2/"; "previous regex")]
+    fn missing_group(script: &str) {
+        assert!(Program::from_str(script).is_err());
+    }
+
+    #[test_case("s/x/y/gg"; "repeated g")]
+    #[test_case("s/x/y/g2g"; "g around the limit")]
+    #[test_case("s/x/y/2i3"; "repeated limit")]
+    #[test_case("s/x/y/2g3"; "limits around g")]
+    fn repeated_substitution_flags(script: &str) {
+        assert!(Program::from_str(script).is_err());
+    }
+
+    #[test_case("s/a/$0 $$3 \\$3/"; "whole match and literal dollars")]
+    #[test_case("s/(a)(b)/$2${1}x $1b/"; "numbers")]
+    #[test_case("s/(?<x>a)(?<y_1>b)/$x ${y_1} $2/"; "names")]
+    #[test_case("s/a/$ ${/"; "lone dollar")]
+    fn existing_group(script: &str) {
+        assert!(Program::from_str(script).is_ok());
+    }
+
+    #[test_case("s/a/b/ t end"; "label")]
+    #[test_case("s/a/b/ t p"; "command")]
+    #[test_case("s/a/b/ T end"; "label after T")]
+    fn branch_not_ending_instruction(script: &str) {
+        assert!(Program::from_str(script).is_err());
+    }
+
+    #[test_case("s/a/b/ t"; "end of script")]
+    #[test_case("s/a/b/ t ; p"; "semicolon")]
+    #[test_case("s/a/b/ t # comment"; "comment")]
+    #[test_case("s/a/b/ t\np"; "newline")]
+    #[test_case("s/a/b/ T ; p"; "T")]
+    fn branch_ending_instruction(script: &str) {
+        assert!(Program::from_str(script).is_ok());
+    }
+
+    #[test_case("p\n__END__\na\nb\n", Some("a\nb\n"); "data")]
+    #[test_case("p\r\n__END__\r\na\r\n", Some("a\r\n"); "crlf")]
+    #[test_case("p\n__END__\n", Some(""); "empty data")]
+    #[test_case("p\n'__END__'\n", None; "no sentinel")]
+    fn parse_file_with_data(script: &str, data: Option<&str>) {
+        let file = TempFile::new("data.se", script);
+        let (mut prog, result) = Program::parse_file_with_data(file.path(), false).unwrap();
+        assert_eq!(result.as_deref(), data.map(str::as_bytes));
+        let (output, _) = prog.run_str("x", false).unwrap();
+        assert_eq!(output, if data.is_some() { "x\n" } else { "x\n__END__" });
+    }
+
+    #[test]
+    fn parse_files() {
+        let first = TempFile::new("files-1.se", "/a/ p\n");
+        let second = TempFile::new(
+            "files-2.se",
+            "# uses the regex from the first file\n// s//b/ p\n",
+        );
+        let paths = [first.path().clone(), second.path().clone()];
+        let (mut prog, data) = Program::parse_files_with_data(&paths, false).unwrap();
+        assert_eq!(data, None);
+        let (output, _) = prog.run_str("a\nc", false).unwrap();
+        assert_eq!(output, "a\nb\n");
+    }
+
+    #[test]
+    fn parse_files_data_not_last() {
+        let first = TempFile::new("files-data-1.se", "p\n__END__\nx\n");
+        let second = TempFile::new("files-data-2.se", "p\n");
+        let paths = [first.path().clone(), second.path().clone()];
+        let err = Program::parse_files_with_data(&paths, false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "error in {} at line 2: only the last script can be followed by the __END__ data",
+                first.path().display()
+            )
+        );
+    }
+
+    #[test]
+    fn parse_scripts() {
+        let file = TempFile::new("scripts.se", "s//b/ p\n__END__\na\nc\n");
+        let scripts = [
+            super::Script::Text("/a/ '1'".to_string()),
+            super::Script::File(file.path().clone()),
+        ];
+        let (mut prog, data) = Program::parse_scripts_with_data(&scripts, false).unwrap();
+        assert_eq!(data.as_deref(), Some(b"a\nc\n".as_slice()));
+        let (output, _) = prog.run_str("a\nc", false).unwrap();
+        assert_eq!(output, "1b\nc\n");
+    }
+
+    #[test]
+    fn parse_files_error() {
+        let first = TempFile::new("files-error-1.se", "p\n");
+        let second = TempFile::new("files-error-2.se", "p\n\n1 p ; 2 s/a\n");
+        let paths = [first.path().clone(), second.path().clone()];
+        let err = Program::parse_files_with_data(&paths, false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("error in {} at line 3", second.path().display())
+        );
+    }
+
+    #[test]
+    fn callback_without_name() {
+        assert!(Program::from_str("@ p").is_err());
+    }
+
+    #[test]
+    fn no_previous_regex() {
+        assert!(Program::from_str("s//bar/").is_err());
+    }
+
+    #[test]
+    fn write_without_file() {
+        assert!(Program::from_str("w out.txt").is_err());
+    }
+
+    #[test]
+    fn number_prefix_without_quotes() {
+        assert!(Program::from_str("n 3 -s:").is_err());
+    }
+
+    #[test]
+    fn zero_tab_width() {
+        assert!(Program::from_str("E 0").is_err());
+    }
 }
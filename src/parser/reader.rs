@@ -2,15 +2,20 @@ use super::Error;
 use anyhow::{Result, bail};
 use std::{
     fs::File,
-    io::{BufRead, BufReader, Lines},
+    io::{BufRead, BufReader},
     iter::Peekable,
     path::PathBuf,
     vec::IntoIter,
 };
 
+/// The line ending the script in the file, the following lines are the inline input data.
+pub(crate) const DATA_SENTINEL: &str = "__END__";
+
 pub(crate) trait Reader {
     fn next(&mut self) -> Result<Option<char>>;
     fn peek(&mut self) -> Result<Option<char>>;
+    /// Check if the following characters (in the same line) are `s`, without proceeding.
+    fn starts_with(&mut self, s: &str) -> Result<bool>;
 
     fn skip(&mut self) {
         self.next().unwrap();
@@ -51,22 +56,37 @@ impl Reader for StringReader {
     fn peek(&mut self) -> Result<Option<char>> {
         Ok(self.0.peek().cloned())
     }
+
+    fn starts_with(&mut self, s: &str) -> Result<bool> {
+        Ok(self.0.clone().take(s.chars().count()).eq(s.chars()))
+    }
 }
 
 pub(crate) struct FileReader {
-    file: Lines<BufReader<File>>,
+    file: Box<dyn BufRead>,
     buffer: StringReader,
+    /// Was the script ended by the [`DATA_SENTINEL`] line?
+    ended: bool,
+    /// Number of the lines read so far
+    line: usize,
 }
 
 impl TryFrom<&PathBuf> for FileReader {
     type Error = anyhow::Error;
 
+    /// Open the file, or read from stdin when the path is `-`.
     fn try_from(value: &PathBuf) -> Result<Self, Self::Error> {
-        let file = BufReader::new(File::open(value)?).lines();
+        let file: Box<dyn BufRead> = if value.as_os_str() == "-" {
+            Box::new(std::io::stdin().lock())
+        } else {
+            Box::new(BufReader::new(File::open(value)?))
+        };
         let chars = StringReader::from("");
         Ok(FileReader {
             file,
             buffer: chars,
+            ended: false,
+            line: 0,
         })
     }
 }
@@ -93,16 +113,45 @@ impl Reader for FileReader {
             }
         }
     }
+
+    fn starts_with(&mut self, s: &str) -> Result<bool> {
+        // make sure the line is read into the buffer
+        self.peek()?;
+        self.buffer.starts_with(s)
+    }
 }
 
 impl FileReader {
     fn next_line(&mut self) -> Result<bool> {
-        if let Some(res) = self.file.next() {
-            let mut line = res?;
-            line.push('\n');
-            self.buffer = StringReader::from(line.as_str());
-            return Ok(true);
+        if self.ended {
+            return Ok(false);
         }
-        Ok(false)
+        let mut line = String::new();
+        if self.file.read_line(&mut line)? == 0 {
+            return Ok(false);
+        }
+        self.line += 1;
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line == DATA_SENTINEL {
+            self.ended = true;
+            return Ok(false);
+        }
+        self.buffer = StringReader::from(format!("{line}\n").as_str());
+        Ok(true)
+    }
+
+    /// Number of the line that is being parsed.
+    pub(crate) fn line(&self) -> usize {
+        self.line
+    }
+
+    /// The content of the file following the [`DATA_SENTINEL`] line, if it was present.
+    pub(crate) fn into_data(mut self) -> Result<Option<Vec<u8>>> {
+        if !self.ended {
+            return Ok(None);
+        }
+        let mut data = Vec::new();
+        self.file.read_to_end(&mut data)?;
+        Ok(Some(data))
     }
 }
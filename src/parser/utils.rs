@@ -1,7 +1,7 @@
 use super::{reader::Reader, regex_reader};
 use crate::Regex;
-use anyhow::Result;
-use std::str::FromStr;
+use anyhow::{Result, anyhow, bail};
+use std::{iter::Peekable, str::Chars, str::FromStr};
 
 pub(crate) fn skip_whitespace<R: Reader>(reader: &mut R) {
     while reader
@@ -12,10 +12,41 @@ pub(crate) fn skip_whitespace<R: Reader>(reader: &mut R) {
     }
 }
 
+/// Same as [`skip_whitespace`], but stop at the newline, which separates the instructions.
+pub(crate) fn skip_blanks<R: Reader>(reader: &mut R) {
+    while reader
+        .peek()
+        .is_ok_and(|o| o.is_some_and(|c| c.is_whitespace() && c != '\n'))
+    {
+        reader.skip();
+    }
+}
+
+/// Skip the whitespace, the comments, and the `;` or newline separators between the instructions.
+pub(crate) fn skip_separators<R: Reader>(reader: &mut R) -> Result<()> {
+    loop {
+        skip_comments(reader)?;
+        if !reader.next_is(';')? {
+            return Ok(());
+        }
+    }
+}
+
 pub(crate) fn skip_line<R: Reader>(reader: &mut R) {
     while reader.next().is_ok_and(|o| o.is_some_and(|c| c != '\n')) {}
 }
 
+/// Skip the whitespace and the `#` comments running until the end of the line.
+pub(crate) fn skip_comments<R: Reader>(reader: &mut R) -> Result<()> {
+    loop {
+        skip_whitespace(reader);
+        if !reader.next_is('#')? {
+            return Ok(());
+        }
+        skip_line(reader);
+    }
+}
+
 pub(crate) fn read_integer<R: Reader>(reader: &mut R) -> Result<String> {
     let mut num = String::new();
     loop {
@@ -28,10 +59,206 @@ pub(crate) fn read_integer<R: Reader>(reader: &mut R) -> Result<String> {
     Ok(num)
 }
 
-pub(crate) fn parse_regex<R: Reader>(reader: &mut R) -> Result<Option<Regex>> {
-    let regex = regex_reader::read_regex(reader)?;
+pub(crate) fn read_identifier<R: Reader>(reader: &mut R) -> Result<String> {
+    let mut name = String::new();
+    loop {
+        match reader.peek()? {
+            Some(c) if c.is_alphanumeric() || c == '_' => name.push(c),
+            _ => break,
+        }
+        reader.skip();
+    }
+    Ok(name)
+}
+
+/// State of parsing the regular expressions of the script.
+#[derive(Debug, Default)]
+pub(crate) struct RegexContext {
+    /// The regular expression parsed last, reused by the empty one
+    pub(crate) last: Option<Regex>,
+    /// Treat the regular expressions and the replacements as literal strings
+    pub(crate) fixed_strings: bool,
+}
+
+/// Parse the regular expression, the empty one reuses the last regular expression
+/// that was parsed (if any).
+pub(crate) fn parse_regex<R: Reader>(
+    reader: &mut R,
+    ctx: &mut RegexContext,
+) -> Result<Option<Regex>> {
+    let whole_line = reader.peek()? == Some('^');
+    let mut regex = regex_reader::read_regex(reader)?;
     if regex.is_empty() {
-        return Ok(None);
+        return Ok(ctx.last.clone());
+    }
+    if ctx.fixed_strings {
+        regex = if whole_line {
+            format!("^{}$", regex::escape(&regex[1..regex.len() - 1]))
+        } else {
+            regex::escape(&regex)
+        };
+    }
+    if whole_line {
+        regex = anchor_whole(&regex);
+    }
+    let regex = Regex::from_str(&regex)?;
+    ctx.last = Some(regex.clone());
+    Ok(Some(regex))
+}
+
+/// The `^...$` form needs to match the whole pattern space, also when it contains newlines,
+/// so the anchors are outside of the group isolating the pattern, with the multiline mode
+/// explicitly turned off. This way the flags like `(?m)` set in the pattern do not affect them.
+fn anchor_whole(regex: &str) -> String {
+    let inner = &regex[1..regex.len() - 1];
+    // in verbose mode, the comment at the end would swallow the closing bracket
+    let newline = if is_verbose(inner) { "\n" } else { "" };
+    format!("(?-m)^(?:{inner}{newline})$")
+}
+
+/// Does the pattern turn on the verbose mode, where the whitespace is ignored
+/// and `#` starts a comment?
+pub(crate) fn is_verbose(pattern: &str) -> bool {
+    pattern.split("(?").skip(1).any(|flags| {
+        flags
+            .chars()
+            .take_while(|c| c.is_ascii_alphabetic())
+            .any(|c| c == 'x')
+    })
+}
+
+/// Replace the escape sequences `\n`, `\t`, `\r`, `\b`, `\f`, `\'`, `\"`, `\\`,
+/// the `\uXXXX` or `\u{X...}` Unicode characters, and the `\xHH` hex or `\ooo` octal ASCII characters.
+pub fn unescape(s: &str) -> Result<String> {
+    let mut acc = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            acc.push(c);
+            continue;
+        }
+        let c = match chars.next() {
+            Some('b') => '\u{8}',
+            Some('f') => '\u{c}',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some(c @ ('\'' | '"' | '\\')) => c,
+            Some('u') if chars.next_if_eq(&'{').is_some() => {
+                let digits = take_digits(&mut chars, 16, 6);
+                if digits.is_empty() || chars.next() != Some('}') {
+                    bail!("invalid escape sequence '\\u{{{}' in '{}'", digits, s);
+                }
+                u32::from_str_radix(&digits, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or(anyhow!(
+                        "'\\u{{{}}}' in '{}' is not a valid Unicode code point",
+                        digits,
+                        s
+                    ))?
+            }
+            Some('u') => {
+                let digits = take_digits(&mut chars, 16, 4);
+                u32::from_str_radix(&digits, 16)
+                    .ok()
+                    .filter(|_| digits.len() == 4)
+                    .and_then(char::from_u32)
+                    .ok_or(anyhow!(
+                        "invalid escape sequence '\\u{}' in '{}'",
+                        digits,
+                        s
+                    ))?
+            }
+            Some('x') => {
+                let digits = take_digits(&mut chars, 16, 2);
+                if digits.len() != 2 {
+                    bail!("invalid escape sequence '\\x{}' in '{}'", digits, s);
+                }
+                ascii(&digits, 16, s)?
+            }
+            Some(c) if c.is_digit(8) => {
+                let digits = format!("{c}{}", take_digits(&mut chars, 8, 2));
+                ascii(&digits, 8, s)?
+            }
+            _ => bail!("unrecognized escape characters in '{}'", s),
+        };
+        acc.push(c);
+    }
+    Ok(acc)
+}
+
+/// Take up to `max` digits in the `radix`.
+fn take_digits(chars: &mut Peekable<Chars>, radix: u32, max: usize) -> String {
+    let mut digits = String::new();
+    while digits.len() < max
+        && let Some(c) = chars.next_if(|c| c.is_digit(radix))
+    {
+        digits.push(c);
+    }
+    digits
+}
+
+/// The escaped bytes need to be valid UTF-8 on their own, so only ASCII is allowed.
+fn ascii(digits: &str, radix: u32, s: &str) -> Result<char> {
+    match u32::from_str_radix(digits, radix) {
+        Ok(code) if code < 0x80 => Ok(char::from(code as u8)),
+        _ => bail!(
+            "escaped byte '{}' in '{}' is not an ASCII character, use \\uXXXX instead",
+            digits,
+            s
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::StringReader;
+    use test_case::test_case;
+
+    #[test_case("^abc$", "(?-m)^(?:abc)$"; "whole line")]
+    #[test_case(r"^\$$", r"(?-m)^(?:\$)$"; "escaped dollar")]
+    #[test_case("^(?m)a$", "(?-m)^(?:(?m)a)$"; "multiline flag")]
+    #[test_case("^(?x) a # b\n$", "(?-m)^(?:(?x) a # b\n\n)$"; "verbose")]
+    #[test_case("/^abc$/", "^abc$"; "not whole line")]
+    fn anchor_whole(input: &str, expected: &str) {
+        let regex = super::parse_regex(&mut StringReader::from(input), &mut Default::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(regex.to_string(), expected);
+    }
+
+    #[test_case("abc", "abc"; "no escapes")]
+    #[test_case(r"a\tb\n\r\b\f", "a\tb\n\r\u{8}\u{c}"; "special characters")]
+    #[test_case(r#"\'\"\\"#, "'\"\\"; "quotes and backslash")]
+    #[test_case(r"\x41", "A"; "hex")]
+    #[test_case(r"\x0a\x7F", "\n\u{7f}"; "hex case")]
+    #[test_case(r"\101", "A"; "octal")]
+    #[test_case(r"\0", "\0"; "octal nul")]
+    #[test_case(r"\12x", "\nx"; "short octal")]
+    #[test_case(r"\1011", "A1"; "octal takes three digits")]
+    #[test_case(r"\u0105\u00e9", "ąé"; "unicode")]
+    #[test_case(r"\u{1F600}!", "\u{1F600}!"; "emoji")]
+    #[test_case(r"\u{41}\u{0000e9}", "A\u{e9}"; "braces")]
+    #[test_case(r"\u{10FFFF}", "\u{10FFFF}"; "max code point")]
+    fn unescape(input: &str, expected: &str) {
+        assert_eq!(super::unescape(input).unwrap(), expected);
+    }
+
+    #[test_case(r"\xE9"; "hex not ascii")]
+    #[test_case(r"\351"; "octal not ascii")]
+    #[test_case(r"\x4"; "short hex")]
+    #[test_case(r"\xg1"; "invalid hex")]
+    #[test_case(r"\u12"; "short unicode")]
+    #[test_case(r"\uD800"; "surrogate")]
+    #[test_case(r"\u{D800}"; "surrogate in braces")]
+    #[test_case(r"\u{110000}"; "too large")]
+    #[test_case(r"\u{1234567}"; "too long")]
+    #[test_case(r"\u{}"; "empty braces")]
+    #[test_case(r"\u{41"; "unclosed braces")]
+    #[test_case(r"\q"; "unknown")]
+    #[test_case("\\"; "trailing backslash")]
+    fn invalid(input: &str) {
+        assert!(super::unescape(input).is_err());
     }
-    Ok(Some(Regex::from_str(&regex)?))
 }
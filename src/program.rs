@@ -1,99 +1,889 @@
-use std::io::StdoutLock;
+use crate::{Action, Line, RegexOptions, Status, address::Address, command};
+use anyhow::{Context, Result, bail};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, VecDeque, hash_map::Entry},
+    fmt::Write as _,
+    fs::File,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
 
-use crate::{Action, Line, Status, command};
-use anyhow::Result;
-use std::io::Write;
+const COLOR_START: &str = "\x1b[01;31m\x1b[K";
+const COLOR_END: &str = "\x1b[m\x1b[K";
 
-#[derive(Debug, PartialEq)]
+/// The cloned program has its own state, so the clones can process different inputs
+/// independently (e.g. in parallel).
+#[derive(Debug, Clone, PartialEq)]
 pub struct Program {
     actions: Vec<Action>,
     finally: Vec<command::Command>,
     memory: Memory,
+    unbuffered: bool,
+    /// The trace of the processed line, if tracing is enabled
+    trace: Option<Trace>,
+    /// Number of the lines matched by each of the conditions (parallel to `actions`), if enabled
+    rule_counts: Option<Vec<usize>>,
 }
 
-#[derive(Debug, PartialEq, Default)]
+#[derive(Debug, Clone, PartialEq, Default)]
 pub(crate) struct Memory {
     pub(crate) line: Line,
     pub(crate) this: String,
     pub(crate) hold: String,
+    /// Named hold registers
+    pub(crate) registers: HashMap<String, String>,
+    /// Files to be printed at the end of the cycle
+    pub(crate) queue: Vec<PathBuf>,
+    /// Was any substitution made since reading the line or the last `t` or `T`?
+    pub(crate) substituted: bool,
+    /// Record separator used instead of the newline
+    pub(crate) separator: Option<char>,
+    /// Expand the environment variables in the inserted strings
+    pub(crate) expand_env: bool,
+    /// Highlight the matches of the `matched` address when printing the pattern space
+    pub(crate) color: bool,
+    /// Position in the actions of the last regular expression address that matched the line,
+    /// it is cleared when reading the next line
+    pub(crate) matched: Option<usize>,
+    /// Print the lines as JSON lines records
+    pub(crate) json: bool,
+    /// The input is read in the binary mode, so it may contain the raw bytes
+    pub(crate) binary: bool,
+    /// Files opened by `w`
+    pub(crate) written: WrittenFiles,
+    /// Was the pattern space empty when `B` ran the last time?
+    pub(crate) blank: bool,
+    /// The pattern space (or its fields) when `u` ran the last time
+    pub(crate) uniq: Option<String>,
+    /// Reusable buffer the substitutions write to, it is swapped with the pattern space
+    pub(crate) scratch: String,
+    /// Functions called by `@name`
+    pub(crate) callbacks: Callbacks,
+    /// Written by `w'/dev/stderr'`
+    pub(crate) stderr: SharedWriter,
 }
 
 impl Memory {
     pub(crate) fn read(&mut self, line: Line) {
         self.this = line.1.clone();
         self.line = line;
+        self.substituted = false;
+        self.matched = None;
+    }
+
+    /// The character terminating the printed records.
+    pub(crate) fn separator(&self) -> char {
+        self.separator.unwrap_or('\n')
+    }
+
+    /// The `text` with the matches of the `matched` address wrapped in the ANSI color codes,
+    /// the same way as `grep --color` does it.
+    pub(crate) fn colored<'a>(&self, matched: Option<&Address>, text: &'a str) -> Cow<'a, str> {
+        let Some(regex) = matched
+            .filter(|_| self.color)
+            .and_then(|cond| cond.matched_regex(&self.line))
+        else {
+            return Cow::Borrowed(text);
+        };
+        let mut result = String::new();
+        let mut last = 0;
+        for m in regex.0.find_iter(text).filter(|m| !m.is_empty()) {
+            result.push_str(&text[last..m.start()]);
+            result.push_str(COLOR_START);
+            result.push_str(m.as_str());
+            result.push_str(COLOR_END);
+            last = m.end();
+        }
+        if last == 0 {
+            return Cow::Borrowed(text);
+        }
+        result.push_str(&text[last..]);
+        Cow::Owned(result)
+    }
+
+    /// The groups captured from the line by the `matched` address.
+    pub(crate) fn captures(&self, matched: Option<&Address>) -> Option<regex::Captures<'_>> {
+        matched?.matched_regex(&self.line)?.0.captures(&self.line.1)
+    }
+
+    /// The named hold register, or the default hold space if `name` is not given.
+    pub(crate) fn register(&mut self, name: &Option<String>) -> &mut String {
+        match name {
+            Some(name) => self.registers.entry(name.to_string()).or_default(),
+            None => &mut self.hold,
+        }
+    }
+
+    /// Write the pattern space followed by the separator to the file, `/dev/stdout`
+    /// and `/dev/stderr` stand for the output and the `stderr` writer, even if they do not exist.
+    pub(crate) fn write_file<W: Write>(
+        &self,
+        path: &Path,
+        matched: Option<&Address>,
+        out: &mut W,
+    ) -> Result<()> {
+        let text = format!("{}{}", self.this, self.separator());
+        match path.to_str() {
+            Some("/dev/stdout") if self.json => command::write_json(out, self.line.0, &self.this)?,
+            Some("/dev/stdout") => write!(out, "{}", self.colored(matched, &text))?,
+            Some("/dev/stderr") => self.stderr.write(&text)?,
+            _ => self.written.write(path, &text)?,
+        }
+        Ok(())
+    }
+}
+
+/// The files written by `w`. A file is truncated when it is written for the first time,
+/// and the following writes append to it. The clones of the program share the files,
+/// so when they run in parallel, their lines are interleaved in the order of writing.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct WrittenFiles(Arc<Mutex<HashMap<PathBuf, File>>>);
+
+impl WrittenFiles {
+    fn write(&self, path: &Path, text: &str) -> Result<()> {
+        let mut files = self.0.lock().unwrap();
+        let file = match files.entry(path.to_path_buf()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(
+                File::create(path)
+                    .with_context(|| format!("cannot write to {}", path.display()))?,
+            ),
+        };
+        file.write_all(text.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// The open files are not a part of the program, but the state of running it.
+impl PartialEq for WrittenFiles {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+/// Writer passed by the caller, like the stderr. The clones of the program share it.
+#[derive(Clone)]
+pub(crate) struct SharedWriter(Arc<Mutex<Box<dyn Write + Send>>>);
+
+impl SharedWriter {
+    fn new<W: Write + Send + 'static>(writer: W) -> Self {
+        SharedWriter(Arc::new(Mutex::new(Box::new(writer))))
+    }
+
+    /// Write the `text` and flush it right away, like the stderr does.
+    pub(crate) fn write(&self, text: &str) -> Result<()> {
+        let mut writer = self.0.lock().unwrap();
+        writer.write_all(text.as_bytes())?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// The stderr of the process, unless the caller passes another writer.
+impl Default for SharedWriter {
+    fn default() -> Self {
+        SharedWriter::new(std::io::stderr())
+    }
+}
+
+impl std::fmt::Debug for SharedWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SharedWriter")
+    }
+}
+
+/// The writer is not a part of the program, but the state of running it.
+impl PartialEq for SharedWriter {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+/// The trace of the processed line, written to `out` after the line is processed.
+#[derive(Debug, Clone, PartialEq)]
+struct Trace {
+    buffer: String,
+    out: SharedWriter,
+}
+
+type Callback = dyn Fn(&str) -> String + Send + Sync;
+
+/// The functions registered with [`Program::set_callback`]. The clones of the program share them.
+#[derive(Clone, Default)]
+pub(crate) struct Callbacks(HashMap<String, Arc<Callback>>);
+
+impl Callbacks {
+    pub(crate) fn call(&self, name: &str, text: &str) -> Result<String> {
+        match self.0.get(name) {
+            Some(callback) => Ok(callback(text)),
+            None => bail!("no callback registered as '{name}'"),
+        }
+    }
+}
+
+impl std::fmt::Debug for Callbacks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+/// The functions cannot be compared, so only the names are.
+impl PartialEq for Callbacks {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.len() == other.0.len() && self.0.keys().all(|name| other.0.contains_key(name))
     }
 }
 
 impl Program {
+    /// Build the program from the compiled `actions` and the `finally` commands
+    /// that run after reading all the input.
+    ///
+    /// ```
+    /// use se::{Action, Address, Command, Program};
+    ///
+    /// let mut program = Program::from_actions(
+    ///     vec![
+    ///         Action::Condition(Address::Location(2), 1),
+    ///         Action::Command(Command::Println),
+    ///     ],
+    ///     vec![Command::LineNumber(true)],
+    /// )
+    /// .unwrap();
+    /// let (output, _) = program.run_str("a\nb\nc", false).unwrap();
+    /// assert_eq!(output, "b\n3\n");
+    /// ```
+    ///
+    /// The [`Address::Maybe`] placeholder is an error, since only the parser can replace it.
+    pub fn from_actions(actions: Vec<Action>, finally: Vec<command::Command>) -> Result<Program> {
+        for action in &actions {
+            if let Action::Condition(cond, _) = action
+                && cond.has_placeholder()
+            {
+                bail!("the address '{cond}' needs the ? replaced by the regex of the substitution");
+            }
+        }
+        Ok(Program::new(actions, finally))
+    }
+
     pub(crate) fn new(actions: Vec<Action>, finally: Vec<command::Command>) -> Program {
         Program {
             actions,
             finally,
             memory: Memory::default(),
+            unbuffered: false,
+            trace: None,
+            rule_counts: None,
         }
     }
 
-    pub fn run<R: Iterator<Item = Result<Line>>>(
+    /// The compiled actions, in the order they run for each line.
+    ///
+    /// ```
+    /// use se::{Action, Address, Command, Program};
+    /// use std::str::FromStr;
+    ///
+    /// let program = Program::from_str("/a/ s/a/b/ p ; $ =").unwrap();
+    /// let rendered: Vec<String> = program.actions().iter().map(|a| a.to_string()).collect();
+    /// assert_eq!(rendered, ["/a/", "s/a/b/0", "p"]);
+    /// assert!(matches!(program.actions()[0], Action::Condition(Address::Regex(_), 2)));
+    /// assert!(matches!(program.actions()[2], Action::Command(Command::Println)));
+    /// ```
+    pub fn actions(&self) -> &[Action] {
+        &self.actions
+    }
+
+    /// The commands of the `$` instructions, which run after reading all the input.
+    ///
+    /// ```
+    /// use se::{Command, Program};
+    /// use std::str::FromStr;
+    ///
+    /// let program = Program::from_str("/a/ p ; $ = q 2").unwrap();
+    /// assert_eq!(program.finally(), [Command::LineNumber(true), Command::Quit(2)]);
+    /// ```
+    pub fn finally(&self) -> &[command::Command] {
+        &self.finally
+    }
+
+    /// Run the program over the lines from the `reader`. When `max` is given,
+    /// stop reading the input after `max` lines were matched.
+    pub fn run<R: Iterator<Item = Result<Line>>, W: Write>(
         &mut self,
         reader: &mut R,
         print_all: bool,
-        out: &mut StdoutLock,
+        max: Option<usize>,
+        out: &mut W,
     ) -> Result<(Status, usize)> {
         use Status::*;
 
+        self.reset();
+
         let mut matches = 0;
         let mut status = Normal;
 
         while let Some(line) = reader.next() {
-            self.memory.read(line?);
             status = Normal;
 
-            if let Some(s) = self.process_line(reader, out)? {
+            if let Some(s) = self.cycle(line?, reader, print_all, out)? {
                 status = s;
                 matches += 1;
             }
+            if self.unbuffered {
+                out.flush()?;
+            }
+            self.flush_trace(out)?;
+
+            if status.is_quit() || max.is_some_and(|max| matches >= max) {
+                break;
+            }
+        }
+
+        // like in sed, quitting stops the program without running the finally block
+        if status.is_quit() {
+            return Ok((status, matches));
+        }
+        if let Some(s) = self.run_finally(reader, out)? {
+            status = s;
+        }
+
+        Ok((status, matches))
+    }
 
-            if status == NoPrint {
+    /// Rather than printing the output, write to `out` the names of the files (`-` for stdin)
+    /// having at least one line matched by the program, like `grep -l`. After the match,
+    /// the remaining lines of the file are skipped. The finally block is not run.
+    /// Returns the status and the number of the matching files.
+    pub fn files_with_matches<R: Iterator<Item = Result<Line>>, W: Write>(
+        &mut self,
+        reader: &mut R,
+        out: &mut W,
+    ) -> Result<(Status, usize)> {
+        self.reset();
+        let mut matched = None;
+        let mut files = 0;
+        while let Some(line) = reader.next() {
+            let line = line?;
+            if matched.as_ref() == Some(&line.2) {
                 continue;
             }
-            if print_all {
-                writeln!(out, "{}", self.memory.this)?;
+            let path = line.2.clone();
+            if let Some(status) = self.cycle(line, reader, false, &mut std::io::sink())? {
+                match &path {
+                    Some(path) => writeln!(out, "{}", path.display())?,
+                    None => writeln!(out, "-")?,
+                }
+                files += 1;
+                if status.is_quit() {
+                    return Ok((status, files));
+                }
+                matched = Some(path);
+                // the ranges that were opened do not leak to the next file
+                self.reset();
             }
-            if let Quit(_) = status {
-                break;
+        }
+        Ok((Status::Normal, files))
+    }
+
+    /// Process a single input line, including printing it if `print_all` is set.
+    fn cycle<R: Iterator<Item = Result<Line>>, W: Write>(
+        &mut self,
+        line: Line,
+        reader: &mut R,
+        print_all: bool,
+        out: &mut W,
+    ) -> Result<Option<Status>> {
+        self.memory.read(line);
+        let status = self.process_line(reader, out)?;
+        if print_all && !status.is_some_and(|s| s.is_deleted()) {
+            if self.memory.json {
+                command::write_json(out, self.memory.line.0, &self.memory.this)?;
+            } else {
+                let matched = matched_condition(&self.actions, self.memory.matched);
+                write!(
+                    out,
+                    "{}{}",
+                    self.memory.colored(matched, &self.memory.this),
+                    self.memory.separator()
+                )?;
+            }
+        }
+        if let Some(Status::QuitSilent(_)) = status {
+            self.memory.queue.clear();
+        }
+        self.flush_queue(out)?;
+        Ok(status)
+    }
+
+    /// Print the content of the queued files, the files that cannot be opened are ignored.
+    /// In JSON mode, each line of the file becomes a separate record.
+    fn flush_queue<W: Write>(&mut self, out: &mut W) -> Result<()> {
+        for path in self.memory.queue.drain(..) {
+            if let Ok(mut file) = File::open(path) {
+                if self.memory.json {
+                    let mut content = String::new();
+                    file.read_to_string(&mut content)?;
+                    for line in content.lines() {
+                        command::write_json(out, self.memory.line.0, line)?;
+                    }
+                } else {
+                    std::io::copy(&mut file, out)?;
+                }
             }
         }
+        Ok(())
+    }
 
-        for cmd in self.finally.iter() {
-            let s = cmd.run(&mut self.memory, reader, out)?;
+    /// Run the finally block. Any command returning a status other than normal stops it,
+    /// but only quitting is returned, so `q` can set the exit code, while `d` or `.` just end the block.
+    fn run_finally<R: Iterator<Item = Result<Line>>, W: Write>(
+        &mut self,
+        reader: &mut R,
+        out: &mut W,
+    ) -> Result<Option<Status>> {
+        let mut status = None;
+        for (pos, cmd) in self.finally.iter().enumerate() {
+            let s = cmd
+                .run(&mut self.memory, None, reader, out)
+                .with_context(|| format!("error in finally command {} ('{cmd}')", pos + 1))?;
             if s != Status::Normal {
-                status = s;
+                status = s.is_quit().then_some(s);
                 break;
             }
         }
+        if let Some(Status::QuitSilent(_)) = status {
+            self.memory.queue.clear();
+        }
+        self.flush_queue(out)?;
+        Ok(status)
+    }
+
+    /// Lazily transform the lines from the `reader`, the output lines
+    /// (without the trailing newlines) are produced on demand.
+    ///
+    /// ```
+    /// use se::{Line, Program};
+    /// use std::str::FromStr;
+    ///
+    /// let mut program = Program::from_str("s/cat/dog/ p").unwrap();
+    /// let reader = ["a cat", "a bird"]
+    ///     .iter()
+    ///     .enumerate()
+    ///     .map(|(i, s)| Ok(Line::new(i + 1, *s)));
+    /// let mut lines = program.transform(reader, false);
+    /// assert_eq!(lines.next().unwrap().unwrap(), "a dog");
+    /// assert_eq!(lines.next().unwrap().unwrap(), "a bird");
+    /// assert!(lines.next().is_none());
+    /// ```
+    pub fn transform<R: Iterator<Item = Result<Line>>>(
+        &mut self,
+        reader: R,
+        print_all: bool,
+    ) -> Transform<'_, R> {
+        self.reset();
+        Transform {
+            program: self,
+            reader,
+            print_all,
+            buffer: Vec::new(),
+            queue: VecDeque::new(),
+            finished: false,
+        }
+    }
 
-        Ok((status, matches))
+    /// Run the program over the lines of the `input` string and return
+    /// the produced output together with the final status.
+    ///
+    /// ```
+    /// use se::{Program, Status};
+    /// use std::str::FromStr;
+    ///
+    /// let mut program = Program::from_str("s/cat/dog/").unwrap();
+    /// let (output, status) = program.run_str("a cat\nand\nother cat", true).unwrap();
+    /// assert_eq!(output, "a dog\nand\nother dog\n");
+    /// assert_eq!(status, Status::Normal);
+    /// ```
+    pub fn run_str(&mut self, input: &str, print_all: bool) -> Result<(String, Status)> {
+        let mut reader = input
+            .lines()
+            .enumerate()
+            .map(|(i, s)| Ok(Line::new(i + 1, s)));
+        let mut out = Vec::new();
+        let (status, _) = self.run(&mut reader, print_all, None, &mut out)?;
+        Ok((String::from_utf8(out)?, status))
     }
 
-    fn process_line<R: Iterator<Item = Result<Line>>>(
+    /// Run the program over the lines from the `reader`, but rather than writing the output,
+    /// collect it per input line together with the final status. The output of the finally
+    /// block, if any, is an additional outcome with the number of the last line.
+    ///
+    /// ```
+    /// use se::{Line, Program, Status};
+    /// use std::str::FromStr;
+    ///
+    /// let mut program = Program::from_str("/cat/ s/cat/dog/ p").unwrap();
+    /// let mut reader = ["a cat", "a bird"]
+    ///     .iter()
+    ///     .enumerate()
+    ///     .map(|(i, s)| Ok(Line::new(i + 1, *s)));
+    /// let (outcomes, status) = program.outcomes(&mut reader, false).unwrap();
+    /// for outcome in &outcomes {
+    ///     println!("{}: {:?} (matched: {})", outcome.line, outcome.text, outcome.matched);
+    /// }
+    /// assert_eq!(outcomes[0].text, "a dog\n");
+    /// assert!(outcomes[0].matched);
+    /// assert_eq!(outcomes[1].text, "");
+    /// assert!(!outcomes[1].matched);
+    /// assert_eq!(status, Status::Normal);
+    /// ```
+    pub fn outcomes<R: Iterator<Item = Result<Line>>>(
         &mut self,
         reader: &mut R,
-        out: &mut StdoutLock,
+        print_all: bool,
+    ) -> Result<(Vec<Outcome>, Status)> {
+        self.reset();
+
+        let mut outcomes = Vec::new();
+        let mut status = Status::Normal;
+
+        while let Some(line) = reader.next() {
+            let line = line?;
+            let num = line.0;
+            let mut out = Vec::new();
+            let matched = self.cycle(line, reader, print_all, &mut out)?;
+            self.flush_trace(&mut out)?;
+            outcomes.push(Outcome {
+                line: num,
+                text: String::from_utf8(out)?,
+                matched: matched.is_some(),
+            });
+            status = matched.unwrap_or(Status::Normal);
+            if status.is_quit() {
+                return Ok((outcomes, status));
+            }
+        }
+
+        let mut out = Vec::new();
+        if let Some(s) = self.run_finally(reader, &mut out)? {
+            status = s;
+        }
+        if !out.is_empty() {
+            outcomes.push(Outcome {
+                line: self.memory.line.0,
+                text: String::from_utf8(out)?,
+                matched: false,
+            });
+        }
+
+        Ok((outcomes, status))
+    }
+
+    /// Terminate the records printed by `p` and the auto-print with `separator`
+    /// rather than the newline.
+    pub fn set_separator(&mut self, separator: char) {
+        self.memory.separator = Some(separator);
+    }
+
+    /// Log the evaluated addresses, the commands that were run, and their statuses
+    /// for each processed line to the `writer` (e.g. stderr).
+    pub fn set_trace<W: Write + Send + 'static>(&mut self, writer: W) {
+        self.trace = Some(Trace {
+            buffer: String::new(),
+            out: SharedWriter::new(writer),
+        });
+    }
+
+    /// Write the trace of the processed line. The output is flushed first,
+    /// so that they don't get mixed up when both are printed to the terminal.
+    fn flush_trace<W: Write>(&mut self, out: &mut W) -> Result<()> {
+        if let Some(trace) = &mut self.trace
+            && !trace.buffer.is_empty()
+        {
+            out.flush()?;
+            trace.out.write(&trace.buffer)?;
+            trace.buffer.clear();
+        }
+        Ok(())
+    }
+
+    /// When set, count how many lines were matched by each of the addresses,
+    /// see [`Program::write_rule_counts`].
+    pub fn set_count_by_rule(&mut self, count: bool) {
+        self.rule_counts = count.then(|| vec![0; self.actions.len()]);
+    }
+
+    /// Write to the `report` the number of lines matched by each of the addresses
+    /// across all the runs, one address per line in the order they appear in the script.
+    pub fn write_rule_counts<W: Write>(&self, report: &mut W) -> Result<()> {
+        if let Some(counts) = &self.rule_counts {
+            for (action, count) in self.actions.iter().zip(counts) {
+                if let Action::Condition(cond, _) = action {
+                    writeln!(report, "{count}\t{cond}")?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Warn in the `report` about the addresses that did not match any line across all the runs,
+    /// which often is a sign of a typo. Needs [`Program::set_count_by_rule`] to be enabled.
+    pub fn write_unused_rules<W: Write>(&self, report: &mut W) -> Result<()> {
+        if let Some(counts) = &self.rule_counts {
+            for (action, count) in self.actions.iter().zip(counts) {
+                if let Action::Condition(cond, _) = action
+                    && *count == 0
+                {
+                    writeln!(
+                        report,
+                        "warning: the address '{cond}' did not match any line"
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Compile all the regular expressions of the program again using the `options`.
+    ///
+    /// ```
+    /// use se::{Program, RegexOptions};
+    /// use std::str::FromStr;
+    ///
+    /// // `r` appends the next line to the pattern space
+    /// let mut program = Program::from_str("r s/a.b/x/ p").unwrap();
+    /// let options = RegexOptions {
+    ///     dotall: true,
+    ///     ..Default::default()
+    /// };
+    /// program.set_regex_options(options).unwrap();
+    /// let (output, _) = program.run_str("a\nb", false).unwrap();
+    /// assert_eq!(output, "x\n");
+    /// ```
+    pub fn set_regex_options(&mut self, options: RegexOptions) -> Result<()> {
+        for action in self.actions.iter_mut() {
+            match action {
+                Action::Condition(addr, _) => addr.set_regex_options(options)?,
+                Action::Command(cmd) => cmd.set_regex_options(options)?,
+            }
+        }
+        for cmd in self.finally.iter_mut() {
+            cmd.set_regex_options(options)?;
+        }
+        Ok(())
+    }
+
+    /// When set, `$VAR` and `${VAR}` in the inserted strings are replaced
+    /// with the values of the environment variables when printing them.
+    pub fn set_expand_env(&mut self, expand: bool) {
+        self.memory.expand_env = expand;
+    }
+
+    /// Register the `callback` run by the `@name` command of the script, which replaces
+    /// the pattern space with the result of calling it on the pattern space.
+    /// Running `@name` without registering the callback is an error.
+    ///
+    /// ```
+    /// use se::Program;
+    /// use std::str::FromStr;
+    ///
+    /// let mut program = Program::from_str("/cat/ @upper p").unwrap();
+    /// program.set_callback("upper", |s| s.to_uppercase());
+    /// let (output, _) = program.run_str("a cat\na dog", false).unwrap();
+    /// assert_eq!(output, "A CAT\n");
+    /// ```
+    pub fn set_callback<F>(&mut self, name: &str, callback: F)
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.memory
+            .callbacks
+            .0
+            .insert(name.to_string(), Arc::new(callback));
+    }
+
+    /// Write the output of `w'/dev/stderr'` to the `writer` rather than to the stderr.
+    pub fn set_stderr<W: Write + Send + 'static>(&mut self, writer: W) {
+        self.memory.stderr = SharedWriter::new(writer);
+    }
+
+    /// Set the initial content of the hold space.
+    pub fn set_hold(&mut self, hold: &str) {
+        self.memory.hold = hold.to_string();
+    }
+
+    /// When set, the parts of the lines matched by the regular expression
+    /// addresses are highlighted when printing them.
+    pub fn set_color(&mut self, color: bool) {
+        self.memory.color = color;
+    }
+
+    /// When set, `l` shows the bytes of the input that were not valid UTF-8 as they were,
+    /// like it is needed when the input was read in the binary mode.
+    pub fn set_binary(&mut self, binary: bool) {
+        self.memory.binary = binary;
+    }
+
+    /// When set, everything that is printed is written as `{"line": N, "text": "..."}`
+    /// JSON lines records, where `N` is the number of the line being processed.
+    /// The line numbers printed by `=` are skipped, since they are already a part of the records.
+    pub fn set_json(&mut self, json: bool) {
+        self.memory.json = json;
+    }
+
+    /// When set, the output is flushed after processing each line.
+    pub fn set_unbuffered(&mut self, unbuffered: bool) {
+        self.unbuffered = unbuffered;
+    }
+
+    /// Set the line-wrap width for the `l` commands that don't specify it explicitly.
+    pub fn set_line_wrap(&mut self, width: usize) {
+        let commands = self
+            .actions
+            .iter_mut()
+            .filter_map(|action| match action {
+                Action::Command(cmd) => Some(cmd),
+                _ => None,
+            })
+            .chain(self.finally.iter_mut());
+        for cmd in commands {
+            if let command::Command::Escapeln(w @ None) = cmd {
+                *w = Some(width);
+            }
+        }
+    }
+
+    /// Reset the state of the range addresses, so that ranges opened
+    /// in the previous run do not leak to the next one.
+    pub fn reset(&self) {
+        for action in self.actions.iter() {
+            if let Action::Condition(addr, _) = action {
+                addr.reset();
+            }
+        }
+    }
+
+    /// Rather than running the program, write to the `report` which addresses matched
+    /// each of the lines, and what would the substitutions, deletions, and prints do.
+    /// The external commands are not executed.
+    pub fn dry_run<R: Iterator<Item = Result<Line>>, W: Write>(
+        &mut self,
+        reader: &mut R,
+        report: &mut W,
+    ) -> Result<()> {
+        self.reset();
+        while let Some(line) = reader.next() {
+            self.memory.read(line?);
+            if let Some(Status::Quit(_) | Status::QuitSilent(_)) =
+                self.dry_run_line(reader, report)?
+            {
+                break;
+            }
+            self.memory.queue.clear();
+        }
+        Ok(())
+    }
+
+    fn dry_run_line<R: Iterator<Item = Result<Line>>, W: Write>(
+        &mut self,
+        reader: &mut R,
+        report: &mut W,
     ) -> Result<Option<Status>> {
+        use command::Command::*;
+
+        let num = self.memory.line.0;
+        let mut pos = 0;
+        while pos < self.actions.len() {
+            match &self.actions[pos] {
+                Action::Condition(cond, jump) => {
+                    if !cond.matches(&self.memory.line) {
+                        pos += jump;
+                    } else if *cond != Address::Always {
+                        if cond.has_regex() {
+                            self.memory.matched = Some(pos);
+                        }
+                        writeln!(report, "{num}: {cond} matched")?;
+                    }
+                }
+                Action::Command(cmd @ Substitute { .. }) => {
+                    let before = self.memory.this.clone();
+                    cmd.run(&mut self.memory, None, reader, &mut std::io::sink())?;
+                    if before != self.memory.this {
+                        writeln!(report, "{num}: {cmd} {before:?} -> {:?}", self.memory.this)?;
+                    }
+                }
+                Action::Command(
+                    cmd @ (Println | Print(_) | Escapeln(_) | LineNumber(_) | PrintFilename
+                    | Insert(_)),
+                ) => {
+                    let matched = matched_condition(&self.actions, self.memory.matched);
+                    let mut buf = Vec::new();
+                    cmd.run(&mut self.memory, matched, reader, &mut buf)?;
+                    let printed = String::from_utf8_lossy(&buf);
+                    writeln!(report, "{num}: {cmd} would print {printed:?}")?;
+                }
+                Action::Command(cmd @ Eval { .. }) => {
+                    writeln!(report, "{num}: {cmd} would run the command")?;
+                }
+                Action::Command(cmd @ WriteFile(_)) => {
+                    writeln!(report, "{num}: {cmd} would write {:?}", self.memory.this)?;
+                }
+                Action::Command(cmd) => {
+                    let s = cmd.run(&mut self.memory, None, reader, &mut std::io::sink())?;
+                    let effect = match s {
+                        Status::Normal => None,
+                        Status::NoPrint => Some("would delete the line"),
+                        Status::Break => Some("would stop processing the line"),
+                        Status::Quit(_) | Status::QuitSilent(_) => Some("would quit"),
+                    };
+                    if let Some(effect) = effect {
+                        writeln!(report, "{num}: {cmd} {effect}")?;
+                        return Ok(Some(s));
+                    }
+                }
+            }
+            pos += 1;
+        }
+        Ok(None)
+    }
+
+    fn process_line<R: Iterator<Item = Result<Line>>, W: Write>(
+        &mut self,
+        reader: &mut R,
+        out: &mut W,
+    ) -> Result<Option<Status>> {
+        let num = self.memory.line.0;
         let mut status = None;
         let mut pos = 0;
         while pos < self.actions.len() {
             match &self.actions[pos] {
                 Action::Condition(cond, jump) => {
-                    if cond.matches(&self.memory.line) {
+                    let matched = cond.matches(&self.memory.line);
+                    if let Some(trace) = &mut self.trace {
+                        let result = if matched { "matched" } else { "skipped" };
+                        writeln!(trace.buffer, "{num}: {cond} {result}")?;
+                    }
+                    if matched {
+                        if let Some(counts) = &mut self.rule_counts {
+                            counts[pos] += 1;
+                        }
+                        if cond.has_regex() {
+                            self.memory.matched = Some(pos);
+                        }
                         status = Some(Status::Normal);
                     } else {
                         pos += jump;
                     }
                 }
                 Action::Command(cmd) => {
-                    let s = cmd.run(&mut self.memory, reader, out)?;
+                    let matched = matched_condition(&self.actions, self.memory.matched);
+                    // the position matches the line of the action in the `--dump` output
+                    let s = cmd
+                        .run(&mut self.memory, matched, reader, out)
+                        .with_context(|| format!("error in command {} ('{cmd}')", pos + 1))?;
+                    if let Some(trace) = &mut self.trace {
+                        writeln!(trace.buffer, "{num}: {cmd} -> {s:?}")?;
+                    }
                     if s != Status::Normal {
                         status = Some(s);
                         break;
@@ -106,22 +896,151 @@ impl Program {
     }
 }
 
+/// The address of the condition at the `pos` in the `actions`.
+fn matched_condition(actions: &[Action], pos: Option<usize>) -> Option<&Address> {
+    match actions.get(pos?)? {
+        Action::Condition(cond, _) => Some(cond),
+        Action::Command(_) => None,
+    }
+}
+
+/// Output produced for a single input line, returned by [`Program::outcomes`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    /// Number of the input line
+    pub line: usize,
+    /// Everything printed while processing the line, including the newlines
+    pub text: String,
+    /// Whether any of the addresses matched the line
+    pub matched: bool,
+}
+
+/// Iterator returned by [`Program::transform`].
+pub struct Transform<'a, R> {
+    program: &'a mut Program,
+    reader: R,
+    print_all: bool,
+    buffer: Vec<u8>,
+    queue: VecDeque<String>,
+    finished: bool,
+}
+
+impl<R: Iterator<Item = Result<Line>>> Transform<'_, R> {
+    /// Process the next input line, or finish processing if there is none.
+    fn step(&mut self) -> Result<()> {
+        let status = match self.reader.next() {
+            Some(line) => {
+                self.program
+                    .cycle(line?, &mut self.reader, self.print_all, &mut self.buffer)?
+            }
+            None => {
+                // the finally block runs only at the end of the input
+                self.finished = true;
+                self.program
+                    .run_finally(&mut self.reader, &mut self.buffer)?;
+                None
+            }
+        };
+        self.program.flush_trace(&mut self.buffer)?;
+        if status.is_some_and(|s| s.is_quit()) {
+            self.finished = true;
+        }
+        self.drain()
+    }
+
+    /// Move the complete lines from the buffer to the queue. When finished,
+    /// the incomplete remainder is moved as well.
+    fn drain(&mut self) -> Result<()> {
+        while let Some(pos) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line = self.buffer.drain(..=pos).take(pos).collect();
+            self.queue.push_back(String::from_utf8(line)?);
+        }
+        if self.finished && !self.buffer.is_empty() {
+            let line = std::mem::take(&mut self.buffer);
+            self.queue.push_back(String::from_utf8(line)?);
+        }
+        Ok(())
+    }
+}
+
+impl<R: Iterator<Item = Result<Line>>> Iterator for Transform<'_, R> {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(line) = self.queue.pop_front() {
+                return Some(Ok(line));
+            }
+            if self.finished {
+                return None;
+            }
+            if let Err(err) = self.step() {
+                self.finished = true;
+                return Some(Err(err));
+            }
+        }
+    }
+}
+
+/// One action per line, with the commands indented below their addresses.
+impl std::fmt::Display for Program {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for action in self.actions.iter() {
+            match action {
+                Action::Condition(..) => writeln!(f, "{action}")?,
+                Action::Command(_) => writeln!(f, "  {action}")?,
+            }
+        }
+        if !self.finally.is_empty() {
+            writeln!(f, "$")?;
+            for cmd in self.finally.iter() {
+                writeln!(f, "  {cmd}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
 impl From<Vec<Action>> for Program {
     fn from(value: Vec<Action>) -> Self {
-        Program {
-            actions: value,
-            finally: Vec::new(),
-            memory: Memory::default(),
-        }
+        Program::new(value, Vec::new())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{Line, Program, lines::MockReader};
-    use std::str::FromStr;
+    use crate::{
+        Action, Address, Between, Command, Line, Program, Status,
+        lines::{MockReader, TempFile},
+    };
+    use std::{
+        io::Write,
+        str::FromStr,
+        sync::{Arc, Mutex},
+    };
     use test_case::test_case;
 
+    /// Writer that can be read after it was passed to the program.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl SharedBuffer {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
     #[test_case(
         "k3-5",
         "345";
@@ -152,11 +1071,654 @@ mod tests {
         "1";
         "first item"
     )]
+    #[test_case(
+        "k -3-",
+        "789";
+        "last items"
+    )]
+    #[test_case(
+        "k 1--2",
+        "1234567";
+        "drop last items"
+    )]
+    #[test_case(
+        "k--2",
+        "1234567";
+        "left-open drop last items"
+    )]
+    #[test_case(
+        "k -4--2",
+        "67";
+        "range from the end"
+    )]
+    #[test_case(
+        "k 2--3",
+        "23456";
+        "mixed range"
+    )]
+    #[test_case(
+        "k -20-",
+        "123456789";
+        "from end out of bounds"
+    )]
+    #[test_case(
+        "k 8--3",
+        "";
+        "empty mixed range"
+    )]
     fn keep(command: &str, expected: &str) {
         let mut prog = Program::from_str(command).unwrap();
-        prog.memory.read(Line(0, "123456789".to_string()));
+        prog.memory.read(Line(0, "123456789".to_string(), None));
+        prog.process_line(&mut MockReader {}, &mut std::io::stdout().lock())
+            .unwrap();
+        assert_eq!(prog.memory.this, expected)
+    }
+
+    #[test_case("kb 3-6", "żó"; "byte range")]
+    #[test_case("kb 4-6", "ó"; "start inside a character")]
+    #[test_case("kb 3-5", "ż"; "end inside a character")]
+    #[test_case("kb -4-", "łć"; "last bytes")]
+    #[test_case("kb -3-", "ć"; "last bytes inside a character")]
+    #[test_case("kb1", "z"; "first byte")]
+    fn keep_bytes(command: &str, expected: &str) {
+        let mut prog = Program::from_str(command).unwrap();
+        prog.memory.read(Line(0, "zażółć".to_string(), None));
+        prog.process_line(&mut MockReader {}, &mut std::io::stdout().lock())
+            .unwrap();
+        assert_eq!(prog.memory.this, expected)
+    }
+
+    #[test]
+    fn dry_run() {
+        let mut prog = Program::from_str("/b/ s/b/x/ p ; 2 d ; e'rm file'").unwrap();
+        let mut reader = ["abc", "bcd"]
+            .iter()
+            .enumerate()
+            .map(|(i, s)| Ok(Line(i + 1, s.to_string(), None)));
+        let mut report = Vec::new();
+        prog.dry_run(&mut reader, &mut report).unwrap();
+        assert_eq!(
+            String::from_utf8(report).unwrap(),
+            [
+                "1: /b/ matched",
+                "1: s/b/x/0 \"abc\" -> \"axc\"",
+                "1: p would print \"axc\\n\"",
+                "1: e'rm file' would run the command",
+                "2: /b/ matched",
+                "2: s/b/x/0 \"bcd\" -> \"xcd\"",
+                "2: p would print \"xcd\\n\"",
+                "2: 2 matched",
+                "2: d would delete the line",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn trace() {
+        let mut prog = Program::from_str("/a/ p ; 2 s/b/x/ d").unwrap();
+        prog.set_trace(std::io::sink());
+        prog.memory.read(Line(2, "abc".to_string(), None));
+        prog.process_line(&mut MockReader {}, &mut std::io::sink())
+            .unwrap();
+        assert_eq!(
+            prog.trace.unwrap().buffer,
+            [
+                "2: /a/ matched",
+                "2: p -> Normal",
+                "2: 2 matched",
+                "2: s/b/x/0 -> Normal",
+                "2: d -> NoPrint",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn trace_writer() {
+        let mut prog = Program::from_str("/a/ p").unwrap();
+        let trace = SharedBuffer::default();
+        prog.set_trace(trace.clone());
+        let (output, _) = prog.run_str("a\nb", false).unwrap();
+        assert_eq!(output, "a\n");
+        assert_eq!(
+            trace.text(),
+            "1: /a/ matched\n1: p -> Normal\n2: /a/ skipped\n"
+        );
+    }
+
+    #[test]
+    fn dump() {
+        let prog = Program::from_str("s/a/b/ ; /x/,3 d ; ?s/c/d/g p ; $ =").unwrap();
+        assert_eq!(
+            prog.to_string(),
+            [
+                "//",
+                "  s/a/b/0",
+                "/x/, 3",
+                "  d",
+                "/c/",
+                "  s/c/d/0",
+                "  p",
+                "$",
+                "  =",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn reset_between_runs() {
+        let mut prog = Program::from_str("/start/-/end/").unwrap();
+        let out = &mut std::io::stdout().lock();
+
+        let mut reader = ["start", "inside"]
+            .iter()
+            .enumerate()
+            .map(|(i, s)| Ok(Line(i + 1, s.to_string(), None)));
+        let (_, matches) = prog.run(&mut reader, false, None, out).unwrap();
+        assert_eq!(matches, 2);
+
+        let mut reader = ["outside"]
+            .iter()
+            .enumerate()
+            .map(|(i, s)| Ok(Line(i + 1, s.to_string(), None)));
+        let (_, matches) = prog.run(&mut reader, false, None, out).unwrap();
+        assert_eq!(matches, 0);
+    }
+
+    #[test_case("2 q", "1\n2\n"; "quit")]
+    #[test_case("2 Q", "1\n"; "quit silently")]
+    #[test_case("5 q", "1\n2\n3\nend"; "end of input")]
+    fn quit(script: &str, expected: &str) {
+        let mut prog = Program::from_str(&format!("{script} ; $ 'end'")).unwrap();
+        let (output, _) = prog.run_str("1\n2\n3", true).unwrap();
+        assert_eq!(output, expected);
+    }
+
+    #[test_case("r ; s/\n/,/ ; p", false, "1,2\n3\n"; "odd number of lines")]
+    #[test_case("r 4 ; s/\n/,/ ; p", false, "1,2,3\n"; "fewer lines than requested")]
+    #[test_case("r", true, "1\n2\n3\n"; "auto-print")]
+    #[test_case("2 r ; $ p", false, "2\n3\n"; "finally")]
+    #[test_case("R p", false, "2\n"; "replace breaks")]
+    #[test_case("R", true, "2\n3\n"; "replace auto-print")]
+    fn read_at_end_of_input(script: &str, print_all: bool, expected: &str) {
+        let mut prog = Program::from_str(script).unwrap();
+        let (output, _) = prog.run_str("1\n2\n3", print_all).unwrap();
+        assert_eq!(output, expected);
+    }
+
+    #[test_case("B", true, "a\n\nb\n\nc\n\n"; "auto-print")]
+    #[test_case("B p", false, "a\n\nb\n\nc\n\n"; "print")]
+    #[test_case("B ; /^$/ '-\n'", false, "-\n-\n-\n"; "following commands do not run")]
+    #[test_case("/[^c]/,/^$/ B p", false, "a\n\nb\n\n"; "only the matched lines")]
+    fn squeeze_blank(script: &str, print_all: bool, expected: &str) {
+        let mut prog = Program::from_str(script).unwrap();
+        let (output, _) = prog.run_str("a\n\n\n\nb\n\nc\n\n\n", print_all).unwrap();
+        assert_eq!(output, expected);
+    }
+
+    #[test_case("u", "a x\nb y\na z\nc\n\n"; "whole line")]
+    #[test_case("u 1", "a x\nb y\na z\nc\n\n"; "first field")]
+    #[test_case("u 2", "a x\nb y\na z\nc\n"; "second field")]
+    #[test_case("u 2 -da", "a x\nb y\na z\nc\n"; "separator")]
+    #[test_case("/a/ u", "a x\nb y\nb y\na z\nc\nc\n\n"; "only the matched lines")]
+    fn uniq(script: &str, expected: &str) {
+        let mut prog = Program::from_str(script).unwrap();
+        let input = "a x\na x\nb y\nb y\na z\nc\nc\n\n";
+        let (output, _) = prog.run_str(input, true).unwrap();
+        assert_eq!(output, expected);
+    }
+
+    #[test_case("$ q 3", "1\n2\n", Status::Quit(3); "quit")]
+    #[test_case("$ Q 4 p", "1\n2\n", Status::QuitSilent(4); "quit silently")]
+    #[test_case("$ p", "1\n2\n2\n", Status::Normal; "print")]
+    #[test_case("$ p q 5 p", "1\n2\n2\n", Status::Quit(5); "print and quit")]
+    #[test_case("$ d p", "1\n2\n", Status::Normal; "delete ends the block")]
+    fn finally_status(script: &str, expected: &str, status: Status) {
+        let mut prog = Program::from_str(script).unwrap();
+        let (output, result) = prog.run_str("1\n2", true).unwrap();
+        assert_eq!(output, expected);
+        assert_eq!(result, status);
+    }
+
+    #[test_case(None, "a1\na2\na3\n", 3; "no limit")]
+    #[test_case(Some(2), "a1\na2\n", 2; "limit")]
+    #[test_case(Some(5), "a1\na2\na3\n", 3; "limit not reached")]
+    fn max_count(max: Option<usize>, expected: &str, count: usize) {
+        let mut prog = Program::from_str("/a/p").unwrap();
+        let mut reader = ["a1", "b", "a2", "b", "a3"]
+            .iter()
+            .enumerate()
+            .map(|(i, s)| Ok(Line(i + 1, s.to_string(), None)));
+        let mut out = Vec::new();
+        let (_, matches) = prog.run(&mut reader, false, max, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+        assert_eq!(matches, count);
+    }
+
+    #[derive(Default)]
+    struct FlushCounter(usize);
+
+    impl std::io::Write for FlushCounter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.0 += 1;
+            Ok(())
+        }
+    }
+
+    #[test_case(false, 0; "buffered")]
+    #[test_case(true, 3; "unbuffered")]
+    fn unbuffered(unbuffered: bool, flushes: usize) {
+        let mut prog = Program::from_str("p").unwrap();
+        prog.set_unbuffered(unbuffered);
+        let mut reader = (1..=3).map(|i| Ok(Line(i, i.to_string(), None)));
+        let mut out = FlushCounter::default();
+        prog.run(&mut reader, false, None, &mut out).unwrap();
+        assert_eq!(out.0, flushes);
+    }
+
+    struct BrokenWriter;
+
+    impl std::io::Write for BrokenWriter {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("broken"))
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test_case("/a/ h p", "error in command 3 ('p')"; "command")]
+    #[test_case("$ = ; h", "error in finally command 1 ('=')"; "finally")]
+    fn command_error(script: &str, expected: &str) {
+        let mut prog = Program::from_str(script).unwrap();
+        let mut reader = [Ok(Line(1, "a".to_string(), None))].into_iter();
+        let err = prog
+            .run(&mut reader, false, None, &mut BrokenWriter)
+            .unwrap_err();
+        assert_eq!(err.to_string(), expected);
+        assert_eq!(err.root_cause().to_string(), "broken");
+    }
+
+    #[test_case("p", true, vec![(1, "a\na\n", true), (2, "b\nb\n", true)], Status::Normal; "print")]
+    #[test_case("/b/ d", true, vec![(1, "a\n", false), (2, "", true)], Status::NoPrint; "delete")]
+    #[test_case("/a/ q5", false, vec![(1, "", true)], Status::Quit(5); "quit")]
+    #[test_case("$ \"end\n\"", false, vec![(1, "", false), (2, "", false), (2, "end\n", false)], Status::Normal; "finally")]
+    fn outcomes(script: &str, print_all: bool, expected: Vec<(usize, &str, bool)>, status: Status) {
+        let mut prog = Program::from_str(script).unwrap();
+        let mut reader = ["a", "b"]
+            .iter()
+            .enumerate()
+            .map(|(i, s)| Ok(Line(i + 1, s.to_string(), None)));
+        let (outcomes, result) = prog.outcomes(&mut reader, print_all).unwrap();
+        let outcomes: Vec<_> = outcomes
+            .iter()
+            .map(|o| (o.line, o.text.as_str(), o.matched))
+            .collect();
+        assert_eq!(outcomes, expected);
+        assert_eq!(result, status);
+    }
+
+    #[test]
+    fn unwritable_file() {
+        let mut prog = Program::from_str("h w'/'").unwrap();
+        let err = prog.run_str("a", false).unwrap_err();
+        assert_eq!(err.to_string(), "error in command 3 ('w'/'')");
+        assert_eq!(err.chain().nth(1).unwrap().to_string(), "cannot write to /");
+    }
+
+    #[test]
+    fn write_file() {
+        let file = TempFile::new("write-file.txt", "old content\n");
+        let script = format!(
+            "w'{0}' ; /b/ w'{0}' ; w'/dev/stdout'",
+            file.path().display()
+        );
+        let mut prog = Program::from_str(&script).unwrap();
+        let (output, _) = prog.run_str("a\nb\nc", false).unwrap();
+        assert_eq!(output, "a\nb\nc\n");
+        // truncated on the first write, and then appended
+        assert_eq!(file.read(), "a\nb\nb\nc\n");
+    }
+
+    #[test]
+    fn clones_run_in_parallel() {
+        let prog = Program::from_str("/start/-/end/ =").unwrap();
+        let handles: Vec<_> = ["a\nstart\nb\nend", "start\nc", "d\ne"]
+            .into_iter()
+            .map(|input| {
+                let mut prog = prog.clone();
+                std::thread::spawn(move || prog.run_str(input, false).unwrap().0)
+            })
+            .collect();
+        let outputs: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(outputs, vec!["2\n3\n4\n", "1\n2\n", ""]);
+    }
+
+    #[test]
+    fn chained_substitutions() {
+        let mut prog =
+            Program::from_str("s/a/AA/g s/(b+)/<$1>/ s/c// s/A/-/3 s/x/y/ s/^/[/ s/$/]/").unwrap();
+        let (output, _) = prog.run_str("abcabc\nbbb\n\nxa\nc", true).unwrap();
+        assert_eq!(output, "[--<b>-A<b>]\n[<bbb>]\n[]\n[y--]\n[]\n");
+    }
+
+    #[test_case("s/a/X/", "XXXX\n", true; "all")]
+    #[test_case("s/a/X/1", "Xaaa\n", true; "first")]
+    #[test_case("s/a/X/2", "XXaa\n", true; "limit")]
+    #[test_case("s/a/X/2g", "aXXX\n", true; "from the second")]
+    #[test_case("s/a/X/g2", "aXXX\n", true; "global before number")]
+    #[test_case("s/a/X/4g", "aaaX\n", true; "from the last")]
+    #[test_case("s/a/X/5g", "aaaa\n", false; "from past the last")]
+    #[test_case("s/(a)/[$1]/3g", "aa[a][a]\n", true; "groups")]
+    #[test_case("s/b*/X/2g", "aXaXaXaX\n", true; "empty matches")]
+    fn substitute_limit(script: &str, expected: &str, substituted: bool) {
+        let mut prog = Program::from_str(&format!("{script} ; t ; 'no change\n'")).unwrap();
+        let (output, _) = prog.run_str("aaaa", true).unwrap();
+        let expected = if substituted {
+            expected.to_string()
+        } else {
+            format!("no change\n{expected}")
+        };
+        assert_eq!(output, expected);
+    }
+
+    #[test_case("r ; s/^/> / ; p", "> a\nb\n"; "default")]
+    #[test_case("r ; s/^/> /m ; p", "> a\n> b\n"; "multiline")]
+    #[test_case("r ; s/^A|B$/x/gmi ; p", "x\nx\n"; "stacked flags")]
+    #[test_case("r ; s/^a$/x/m ; p", "x\nb\n"; "single line anchors")]
+    fn substitute_flags(script: &str, expected: &str) {
+        let mut prog = Program::from_str(script).unwrap();
+        let (output, _) = prog.run_str("a\nb", false).unwrap();
+        assert_eq!(output, expected);
+    }
+
+    #[test_case("r s/a.b/x/ p", false, false, "a\nb\n"; "default")]
+    #[test_case("r s/a.b/x/ p", true, false, "x\n"; "dotall")]
+    #[test_case("r s/(?-s)a.b/x/ p", true, false, "a\nb\n"; "dotall overridden")]
+    #[test_case("r s/^b$/x/ p", false, true, "a\nx\n"; "multiline")]
+    #[test_case("r s/(?-m)^b$/x/ p", false, true, "a\nb\n"; "multiline overridden")]
+    #[test_case("r ; /a/,/b/ s/^a$|b/x/g p", false, true, "x\nx\n"; "multiline fused")]
+    fn regex_options(script: &str, dotall: bool, multiline: bool, expected: &str) {
+        let mut prog = Program::from_str(script).unwrap();
+        prog.set_regex_options(crate::RegexOptions { dotall, multiline })
+            .unwrap();
+        let (output, _) = prog.run_str("a\nb", false).unwrap();
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn named_registers() {
+        let mut prog =
+            Program::from_str("1 h:a ; 2 h:b ; 3 h ; 4 g:a p g:b p x:a p g:a p g p").unwrap();
+        let (output, _) = prog.run_str("one\ntwo\nthree\nfour", false).unwrap();
+        assert_eq!(output, "one\ntwo\none\ntwo\nthree\n");
+    }
+
+    #[test]
+    fn read_file() {
+        let file = TempFile::new("read-file.txt", "file\n");
+        let script = format!("2 r'{}' r'/non/existing/file' ; 3 d", file.path().display());
+        let mut prog = Program::from_str(&script).unwrap();
+        let (output, _) = prog.run_str("1\n2\n3", true).unwrap();
+        assert_eq!(output, "1\n2\nfile\n");
+    }
+
+    #[test_case("/x/ r'{}' q 3", "a\nx\nappended\n", Status::Quit(3); "quit")]
+    #[test_case("/x/ r'{}' Q 4", "a\n", Status::QuitSilent(4); "quit silently")]
+    #[test_case("$ r'{}' q 5", "a\nx\nb\nappended\n", Status::Quit(5); "quit in finally")]
+    fn read_file_and_quit(script: &str, expected: &str, status: Status) {
+        let file = TempFile::new("read-quit.txt", "appended\n");
+        let script = script.replace("{}", &file.path().display().to_string());
+        let mut prog = Program::from_str(&script).unwrap();
+        let (output, result) = prog.run_str("a\nx\nb", true).unwrap();
+        assert_eq!(output, expected);
+        assert_eq!(result, status);
+    }
+
+    #[test_case("/cat/ p", "a cat and a cat", "a \x1b[01;31m\x1b[Kcat\x1b[m\x1b[K and a \x1b[01;31m\x1b[Kcat\x1b[m\x1b[K\n"; "regex")]
+    #[test_case("/c/,/t/ P-", "cat", "\x1b[01;31m\x1b[Kc\x1b[m\x1b[Ka\x1b[01;31m\x1b[Kt\x1b[m\x1b[K"; "set of regexes")]
+    #[test_case("/x*/ p", "cat", "cat\n"; "empty match")]
+    #[test_case("1 p", "cat", "cat\n"; "line number")]
+    #[test_case("/a/ s/a/b/ p", "cat", "cbt\n"; "no match after substitution")]
+    fn color(script: &str, input: &str, expected: &str) {
+        let mut prog = Program::from_str(script).unwrap();
+        prog.set_color(true);
+        let (output, _) = prog.run_str(input, false).unwrap();
+        assert_eq!(output, expected);
+    }
+
+    #[test_case(r"/(\d+)/ 'id=$1\n'", "a 12\nb\n3", "id=12\nid=3\n"; "group")]
+    #[test_case(r"/(\d+)/ ; 'x$1\n'", "a 12\nb", "x12\nx$1\n"; "cleared each cycle")]
+    #[test_case(r"/(?<num>\d+)/ ; /(\w)/ '$num$1\n'", "a 12", "$numa\n"; "last address")]
+    #[test_case(r"/a(x?)/,/b(c)/ '[$1]\n'", "a\nbc", "[]\n[c]\n"; "member of set")]
+    #[test_case(r"/(\d+)/ ; 1 '$1\n'", "a 12", "12\n"; "kept by other addresses")]
+    #[test_case(r"'cost $$5\n'", "a", "cost $$5\n"; "dollars without groups")]
+    #[test_case(r"/a/ 'cost $$5\n'", "a", "cost $5\n"; "dollars with groups")]
+    fn address_captures(script: &str, input: &str, expected: &str) {
+        let mut prog = Program::from_str(script).unwrap();
+        let (output, _) = prog.run_str(input, false).unwrap();
+        assert_eq!(output, expected);
+    }
+
+    #[test_case("/a/", "a.txt\nc.txt\n", Status::Normal; "regex")]
+    #[test_case("/x/", "", Status::Normal; "no matches")]
+    #[test_case("/b/ q 2", "a.txt\n", Status::Quit(2); "quit")]
+    #[test_case("/a1/-/a2/", "a.txt\n", Status::Normal; "range does not leak")]
+    fn files_with_matches(script: &str, expected: &str, status: Status) {
+        let mut prog = Program::from_str(script).unwrap();
+        let mut reader = [
+            ("a.txt", "a1"),
+            ("a.txt", "b"),
+            ("b.txt", "b"),
+            ("c.txt", "a"),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, (path, s))| {
+            let path = Some(std::sync::Arc::new(std::path::PathBuf::from(path)));
+            Ok(Line(i + 1, s.to_string(), path))
+        });
+        let mut out = Vec::new();
+        let (result, files) = prog.files_with_matches(&mut reader, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+        assert_eq!(result, status);
+        assert_eq!(files, expected.lines().count());
+    }
+
+    #[test_case(r#"/(a)/,/(b)/ "got=$1\n""#, "got=a\ngot=b\n"; "groups")]
+    #[test_case(r#"/(?<n>a)/,/(?<n>b)/ "got=${n}\n""#, "got=a\ngot=b\n"; "same group names")]
+    #[test_case(r#"/a/,/b/ "got=$0\n""#, "got=a\ngot=b\n"; "fused")]
+    fn regex_set_captures(script: &str, expected: &str) {
+        let mut prog = Program::from_str(script).unwrap();
+        let (output, _) = prog.run_str("a\nb\nc", false).unwrap();
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn count_by_rule_fused() {
+        let mut prog = Program::from_str("/a/,/b/ p").unwrap();
+        prog.set_count_by_rule(true);
+        prog.run_str("a\nb\nc", false).unwrap();
+        let mut report = Vec::new();
+        prog.write_rule_counts(&mut report).unwrap();
+        assert_eq!(String::from_utf8(report).unwrap(), "2\t/a/, /b/\n");
+    }
+
+    #[test]
+    fn count_by_rule() {
+        let mut prog = Program::from_str("/a/ s/a/x/ ; 2-3,/b/ p ; 9 d").unwrap();
+        prog.set_count_by_rule(true);
+        prog.run_str("a\nb\nab\nc\nba", false).unwrap();
+        let mut report = Vec::new();
+        prog.write_rule_counts(&mut report).unwrap();
+        assert_eq!(
+            String::from_utf8(report).unwrap(),
+            "3\t/a/\n3\t2-3, /b/\n0\t9\n"
+        );
+    }
+
+    #[test]
+    fn callbacks() {
+        let mut prog = Program::from_str("@rev @len p ; @rev p").unwrap();
+        prog.set_callback("rev", |s| s.chars().rev().collect());
+        prog.set_callback("len", |s| format!("{s}:{}", s.len()));
+        let (output, _) = prog.run_str("abc", false).unwrap();
+        assert_eq!(output, "cba:3\n3:abc\n");
+
+        let mut prog = Program::from_str("@missing").unwrap();
+        let err = prog.run_str("abc", false).unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "error in command 2 ('@missing'): no callback registered as 'missing'"
+        );
+    }
+
+    #[test]
+    fn initial_hold() {
+        let mut prog = Program::from_str("1 x p ; 2 j p").unwrap();
+        prog.set_hold("header");
+        let (output, _) = prog.run_str("a\nb", false).unwrap();
+        assert_eq!(output, "header\nb\na\n");
+    }
+
+    #[test]
+    fn unused_rules() {
+        let mut prog = Program::from_str("/a/ p ; /x/ d").unwrap();
+        prog.set_count_by_rule(true);
+        prog.run_str("a\nb", false).unwrap();
+        let mut report = Vec::new();
+        prog.write_unused_rules(&mut report).unwrap();
+        assert_eq!(
+            String::from_utf8(report).unwrap(),
+            "warning: the address '/x/' did not match any line\n"
+        );
+    }
+
+    #[test]
+    fn json() {
+        let mut prog = Program::from_str(r#"1 = p "x\n" ; 2 d"#).unwrap();
+        prog.set_json(true);
+        let (output, _) = prog.run_str("a \"b\"\tc\nd\ne", true).unwrap();
+        assert_eq!(
+            output,
+            [
+                r#"{"line": 1, "text": "a \"b\"\tc"}"#,
+                r#"{"line": 1, "text": "x"}"#,
+                r#"{"line": 1, "text": "a \"b\"\tc"}"#,
+                r#"{"line": 3, "text": "e"}"#,
+                "",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn readln_advances_line_number() {
+        let mut prog = Program::from_str("r 2 =").unwrap();
+        let (output, _) = prog.run_str("1\n2\n3\n4\n5", false).unwrap();
+        assert_eq!(output, "3\n5\n");
+    }
+
+    #[test_case("2 q", &["1", "2"]; "quit")]
+    #[test_case("5 q", &["1", "2", "3", "end"]; "end of input")]
+    fn transform_quit(script: &str, expected: &[&str]) {
+        let mut prog = Program::from_str(&format!("{script} ; $ 'end'")).unwrap();
+        let reader = (1..=3).map(|i| Ok(Line(i, i.to_string(), None)));
+        let lines = prog
+            .transform(reader, true)
+            .map(|l| l.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines, expected);
+    }
+
+    #[test]
+    fn transform_is_lazy() {
+        let mut prog = Program::from_str("p ; $ 'end' ; 2 P-").unwrap();
+        let pulled = std::cell::Cell::new(0);
+        let reader = (1..=3).map(|i| {
+            pulled.set(pulled.get() + 1);
+            Ok(Line(i, i.to_string(), None))
+        });
+
+        let mut lines = prog.transform(reader, true);
+        assert_eq!(pulled.get(), 0);
+        assert_eq!(lines.next().unwrap().unwrap(), "1");
+        assert_eq!(pulled.get(), 1);
+        assert_eq!(lines.next().unwrap().unwrap(), "1");
+        assert_eq!(pulled.get(), 1);
+        assert_eq!(lines.next().unwrap().unwrap(), "2");
+        assert_eq!(lines.next().unwrap().unwrap(), "22");
+        assert_eq!(pulled.get(), 2);
+        assert_eq!(
+            lines.map(|l| l.unwrap()).collect::<Vec<_>>(),
+            vec!["3", "3", "end"]
+        );
+        assert_eq!(pulled.get(), 3);
+    }
+
+    #[test_case("aaa", "baa"; "substituted")]
+    #[test_case("ccc", ""; "not substituted")]
+    fn branch_if_substituted(input: &str, expected: &str) {
+        let mut prog = Program::from_str("s/a/b/1 t ; z").unwrap();
+        prog.memory.read(Line(0, input.to_string(), None));
+        prog.process_line(&mut MockReader {}, &mut std::io::stdout().lock())
+            .unwrap();
+        assert_eq!(prog.memory.this, expected)
+    }
+
+    #[test_case(r"s/(x)/$1/", "x"; "group")]
+    #[test_case(r"s/(x)/$$1/", "$1"; "literal dollar")]
+    #[test_case(r"s/(x)/\$1/", "$1"; "escaped dollar")]
+    #[test_case(r"s/(x)/5$/", "5$"; "trailing dollar")]
+    #[test_case(r"s/(?<name>x)/${name}_/", "x_"; "named group")]
+    fn substitute_template(command: &str, expected: &str) {
+        let mut prog = Program::from_str(command).unwrap();
+        prog.memory.read(Line(0, "x".to_string(), None));
         prog.process_line(&mut MockReader {}, &mut std::io::stdout().lock())
             .unwrap();
         assert_eq!(prog.memory.this, expected)
     }
+
+    #[test_case("aaa", ""; "substituted")]
+    #[test_case("ccc", "ccc"; "not substituted")]
+    fn branch_if_not_substituted(input: &str, expected: &str) {
+        let mut prog = Program::from_str("s/a/b/1 T ; z").unwrap();
+        prog.memory.read(Line(0, input.to_string(), None));
+        prog.process_line(&mut MockReader {}, &mut std::io::stdout().lock())
+            .unwrap();
+        assert_eq!(prog.memory.this, expected);
+        assert!(!prog.memory.substituted)
+    }
+
+    #[test]
+    fn write_stderr() {
+        let mut prog = Program::from_str("w'/dev/stderr' w'/dev/stdout'").unwrap();
+        let stderr = SharedBuffer::default();
+        prog.set_stderr(stderr.clone());
+        let (output, _) = prog.run_str("a\nb", false).unwrap();
+        assert_eq!(output, "a\nb\n");
+        assert_eq!(stderr.text(), "a\nb\n");
+    }
+
+    #[test_case(Address::Maybe; "placeholder")]
+    #[test_case(Address::Negate(Box::new(Address::Maybe)); "negated")]
+    #[test_case(Address::Between(Between::new(Address::Location(1), Address::Maybe)); "range")]
+    #[test_case(Address::Set(vec![Address::Final, Address::Maybe]); "set")]
+    fn from_actions_placeholder(cond: Address) {
+        let actions = vec![
+            Action::Condition(cond, 1),
+            Action::Command(Command::Println),
+        ];
+        assert!(Program::from_actions(actions, Vec::new()).is_err());
+    }
 }
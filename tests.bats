@@ -16,7 +16,7 @@ setup() {
 
 teardown() {
     rm -f /tmp/script.sed
-    rm -f /tmp/{a,b,c}.txt
+    rm -f /tmp/{a,b,c}.txt /tmp/{a,b,c}.txt.gz
 }
 
 @test "Fails with no arguments" {
@@ -38,7 +38,7 @@ teardown() {
 }
 
 @test "Print vs Println" {
-	run diff <(./se 'p' README.md) <(./se 'P\n' README.md)
+	run diff <(./se 'p' README.md) <(./se 'P-\n' README.md)
    [ "$status" -eq 0 ]
 }
 
@@ -72,22 +72,22 @@ teardown() {
 }
 
 @test "Consistent with sed line counting" {
-   run diff <(sed '=' README.md) <(./se '=\np' README.md)
+   run diff <(sed '=' README.md) <(./se '=p' README.md)
    [ "$status" -eq 0 ]
 }
 
 @test "Special characters in template" {
-   run diff <(./se '=\np' README.md) <(./se '="\n"p' README.md)
+   run diff <(./se '=-\np' README.md) <(./se '=-"\n"p' README.md)
    [ "$status" -eq 0 ]
 }
 
 @test "Hex characters" {
-   run diff <(./se '=\np' README.md) <(./se '=\x0Ap' README.md)
+   run diff <(./se '=-\np' README.md) <(./se '=-\x0Ap' README.md)
    [ "$status" -eq 0 ]
 }
 
 @test "Escaped unicode characters" {
-   run diff <(./se '=\x0Ap' README.md) <(./se '=\u000Ap' README.md)
+   run diff <(./se '=-\x0Ap' README.md) <(./se '=-\u000Ap' README.md)
    [ "$status" -eq 0 ]
 }
 
@@ -149,12 +149,12 @@ teardown() {
 }
 
 @test "Count lines like sed" {
-   run diff <(sed -n '$=' README.md) <(./se '$="\n"' README.md)
+   run diff <(sed -n '$=' README.md) <(./se '$=' README.md)
    [ "$status" -eq 0 ]
 }
 
 @test "Stop early" {
-   run diff <(./se '7=q' README.md) <(printf "7")
+   run diff <(./se '7=q' README.md) <(printf "7\n")
    [ "$status" -eq 0 ]
 }
 
@@ -208,6 +208,24 @@ cat <<EOF > /tmp/script.sed
 EOF
 }
 
+@test "Newlines separate the instructions like semicolons" {
+   run diff <(./se $'/sed/ p\n/se/ =\n$ "end\n"' README.md) <(./se '/sed/ p ; /se/ = ; $ "end\n"' README.md)
+   [ "$status" -eq 0 ]
+}
+
+@test "Use multiple script files" {
+   echo '/sed/ p' > /tmp/first.se
+   echo '// =' > /tmp/second.se
+   run diff <(./se -f /tmp/first.se -f /tmp/second.se README.md) <(./se '/sed/ p ; /sed/ =' README.md)
+   [ "$status" -eq 0 ]
+
+   echo 's/a' > /tmp/second.se
+   run ./se -f /tmp/first.se -f /tmp/second.se README.md
+   [ "$status" -ne 0 ]
+   [[ "$output" =~ "error in /tmp/second.se at line 1" ]]
+   rm -f /tmp/first.se /tmp/second.se
+}
+
 @test "Use script file with comments" {
    run create_commented_script
    run diff <(./se -f /tmp/script.sed README.md) <(./se '/sed\` /p' README.md)
@@ -295,3 +313,591 @@ only_for_gsed() {
    run source /tmp/script.sh
    [ "$status" -eq 0 ]
 }
+
+@test "Print file names" {
+   printf "1\n2\n" > /tmp/a.txt
+   echo 3 > /tmp/b.txt
+
+   run diff <(./se '1,3 F' /tmp/a.txt /tmp/b.txt) <(printf "/tmp/a.txt\n/tmp/b.txt\n")
+   [ "$status" -eq 0 ]
+
+   run diff <(echo 1 | ./se 'F') <(printf -- "-\n")
+   [ "$status" -eq 0 ]
+}
+
+@test "Byte order mark is stripped from the input" {
+   run diff <(printf '\xEF\xBB\xBFfoo\nfoo\n' | ./se '^foo$ =-') <(printf "12")
+   [ "$status" -eq 0 ]
+
+   printf '\xEF\xBB\xBFfoo\n' > /tmp/a.txt
+   printf '\xEF\xBB\xBFfoo\n' > /tmp/b.txt
+   run diff <(./se '^foo$ =' /tmp/a.txt /tmp/b.txt) <(printf "1\n2\n")
+   [ "$status" -eq 0 ]
+}
+
+@test "Byte order mark is added to the output" {
+   run diff <(echo foo | ./se --bom 'p') <(printf '\xEF\xBB\xBFfoo\n')
+   [ "$status" -eq 0 ]
+}
+
+@test "Escape like sed" {
+   run diff <(LC_ALL=C sed -n 'l' README.md) <(./se 'l' README.md)
+   [ "$status" -eq 0 ]
+
+   run diff <(LC_ALL=C sed -n -l 20 'l' README.md) <(./se -l 20 'l' README.md)
+   [ "$status" -eq 0 ]
+
+   run diff <(LC_ALL=C sed -n 'l 0' README.md) <(./se 'l0' README.md)
+   [ "$status" -eq 0 ]
+
+   printf 'a\tb\\c\n"it'"'"'s"\n\\\\$\n\a\b\f\v\n' > /tmp/escape.txt
+   run diff <(LC_ALL=C sed -n 'l' /tmp/escape.txt) <(./se 'l' /tmp/escape.txt)
+   [ "$status" -eq 0 ]
+   rm -f /tmp/escape.txt
+}
+
+@test "Read gzipped files" {
+   printf "1\n2\n" | gzip > /tmp/a.txt.gz
+   echo 3 > /tmp/b.txt
+
+   run diff <(./se '=p' /tmp/a.txt.gz /tmp/b.txt) <(printf "1\n1\n2\n2\n3\n3\n")
+   [ "$status" -eq 0 ]
+
+   echo 1 > /tmp/c.txt.gz
+   run ./se 'p' /tmp/c.txt.gz
+   [ "$status" -ne 0 ]
+   [[ "$output" =~ "not a valid gzip file" ]]
+}
+
+@test "Executable script with a shebang" {
+   printf '#!./se -f\n/sed/ p\n' > /tmp/script.sed
+   chmod +x /tmp/script.sed
+   run diff <(/tmp/script.sed README.md) <(./se '/sed/ p' README.md)
+   [ "$status" -eq 0 ]
+}
+
+@test "Eval with the pattern space passed to stdin" {
+   run diff <(./se "e'tr a-z A-Z' P" README.md) <(tr a-z A-Z < README.md)
+   [ "$status" -eq 0 ]
+}
+
+@test "Eval uses the shell from SE_SHELL" {
+   printf '#!/bin/sh\nprintf "wrapped:"\nexec sh "$@"\n' > /tmp/shell.sh
+   chmod +x /tmp/shell.sh
+
+   run diff <(echo 'echo hi' | SE_SHELL=/tmp/shell.sh ./se 'eP') <(echo 'wrapped:hi')
+   [ "$status" -eq 0 ]
+
+   run diff <(echo 'echo hi' | SE_SHELL= ./se 'eP') <(echo 'hi')
+   [ "$status" -eq 0 ]
+   rm -f /tmp/shell.sh
+}
+
+@test "Eval ignoring the exit code" {
+   run ./se 'e' <(printf 'exit 3\necho hi\n')
+   [ "$status" -eq 3 ]
+
+   run diff <(./se 'e!p' <(printf 'printf a; exit 1\nprintf b\n')) <(printf 'a\nb\n')
+   [ "$status" -eq 0 ]
+}
+
+@test "Eval strips the trailing newline" {
+   run diff <(echo 'echo hi' | ./se 'e p') <(echo 'hi')
+   [ "$status" -eq 0 ]
+
+   run diff <(echo 'echo hi' | ./se 'e+ p') <(printf 'hi\n\n')
+   [ "$status" -eq 0 ]
+}
+
+@test "Eval killed by a signal" {
+   run ./se 'e' <(echo 'kill -9 $$')
+   [ "$status" -eq 137 ]
+}
+
+@test "Eval timeout" {
+   run bash -c "echo 'echo oops >&2; sleep 5' | SE_EVAL_TIMEOUT=0.5 ./se 'e'"
+   [ "$status" -ne 0 ]
+   [[ "$output" =~ "oops" ]]
+   [[ "$output" =~ "timed out" ]]
+}
+
+@test "Quit without printing" {
+   run diff <(./se -a '3 Q' README.md) <(head -n 2 README.md)
+   [ "$status" -eq 0 ]
+
+   run ./se -a '3 Q 7' README.md
+   [ "$status" -eq 7 ]
+}
+
+@test "Stop after max count of matches" {
+   run diff <(./se --max-count 3 '/sed/ p' README.md) <(grep -m 3 'sed' README.md)
+   [ "$status" -eq 0 ]
+
+   run ./se -c -m 3 '/sed/' README.md
+   [ "$status" -eq 0 ]
+   [ "$output" -eq 3 ]
+}
+
+@test "Append to hold like sed" {
+   run diff <(sed -n 'H;${x;p}' README.md) <(./se 'H ; $ xp' README.md)
+   [ "$status" -eq 0 ]
+}
+
+@test "Reverse lines like tac using G" {
+   run diff <(tac README.md) <(./se '!1 G ; h ; $ p' README.md)
+   [ "$status" -eq 0 ]
+}
+
+@test "Read file like sed" {
+   echo 'appended' > /tmp/a.txt
+   run diff <(sed '/sed/ r /tmp/a.txt' README.md) <(./se -a "/sed/ r'/tmp/a.txt'" README.md)
+   [ "$status" -eq 0 ]
+
+   run diff <(sed '/sed/ r /non/existing/file' README.md) <(./se -a "/sed/ r'/non/existing/file'" README.md)
+   [ "$status" -eq 0 ]
+}
+
+@test "Fields like cut" {
+   run diff <(cut -d: -f1,3 /etc/passwd) <(./se 'c1,3 -d: p' /etc/passwd)
+   [ "$status" -eq 0 ]
+}
+
+@test "Missing final newline like sed" {
+   run diff <(printf 'a\nb' | sed p) <(printf 'a\nb' | ./se -a p)
+   [ "$status" -eq 0 ]
+
+   printf 'a' > /tmp/a.txt
+   printf 'b' > /tmp/b.txt
+   run diff <(sed p /tmp/a.txt /tmp/b.txt) <(./se -a p /tmp/a.txt /tmp/b.txt)
+   [ "$status" -eq 0 ]
+}
+
+@test "CRLF line endings" {
+   run diff <(printf 'a\r\nb\r\n' | ./se -a 's/$/!/') <(printf 'a!\nb!\n')
+   [ "$status" -eq 0 ]
+
+   run diff <(printf 'a\r\nb\r\n' | ./se --crlf -a 's/$/!/') <(printf 'a!\r\nb!\r\n')
+   [ "$status" -eq 0 ]
+
+   run diff <(printf 'a\nb\n' | ./se --crlf -a 's/$/!/') <(printf 'a!\r\nb!\r\n')
+   [ "$status" -eq 0 ]
+}
+
+@test "Binary input" {
+   run ./se -a 's/a/x/' <(printf 'a\xffb\n')
+   [ "$status" -ne 0 ]
+
+   run diff <(printf 'x\xffb\n') <(printf 'a\xffb\n' | ./se --binary -a 's/a/x/')
+   [ "$status" -eq 0 ]
+}
+
+@test "Custom record separator" {
+   run diff <(printf 'a;X;c;') <(printf 'a;b;c;' | ./se --record-sep ';' -a 's/b/X/')
+   [ "$status" -eq 0 ]
+
+   run diff <(printf 'a\0b\0' | sed -z '/b/p') <(printf 'a\0b\0' | ./se --record-sep '\0' -a '/b/p')
+   [ "$status" -eq 0 ]
+}
+
+@test "Records split by a regex" {
+   run diff <(printf 'three error\nfour\n') <(printf 'one\ntwo\n\nthree error\nfour\n\n\nfive' | ./se --record-regex '^$' '/error/ p')
+   [ "$status" -eq 0 ]
+}
+
+@test "Dry run" {
+   run ./se --dry-run '/b/ s/b/x/ p ; 2 d' <(printf 'abc\nbcd\n')
+   [ "$status" -eq 0 ]
+   [ "${lines[0]}" = '1: /b/ matched' ]
+   [ "${lines[1]}" = '1: s/b/x/0 "abc" -> "axc"' ]
+   [ "${lines[7]}" = '2: d would delete the line' ]
+}
+
+@test "Trace" {
+   run diff <(printf 'abc\n') <(printf 'abc\n' | ./se --trace '/a/ p' 2>/dev/null)
+   [ "$status" -eq 0 ]
+
+   run ./se --trace '/a/ p' <(printf 'abc\n')
+   [ "${lines[0]}" = 'abc' ]
+   [ "${lines[1]}" = '1: /a/ matched' ]
+   [ "${lines[2]}" = '1: p -> Normal' ]
+}
+
+@test "Dump the script" {
+   run ./se --dump '?s/c/d/ p ; $ ='
+   [ "$status" -eq 0 ]
+   [ "$output" = "$(printf '/c/\n  s/c/d/0\n  p\n$\n  =')" ]
+}
+
+@test "Expand environment variables" {
+   run ./se --expand-env '1 "[$SE_TEST_VAR|${SE_TEST_UNSET}|\$SE_TEST_VAR]\n"' <(echo)
+   [ "$output" = '[||$SE_TEST_VAR]' ]
+
+   SE_TEST_VAR=value run ./se --expand-env '1 "[$SE_TEST_VAR]\n"' <(echo)
+   [ "$output" = '[value]' ]
+
+   SE_TEST_VAR=value run ./se '1 "[$SE_TEST_VAR]\n"' <(echo)
+   [ "$output" = '[$SE_TEST_VAR]' ]
+
+   run ./se '1 "[\$x|$$x]\n"' <(echo)
+   [ "$output" = '[$x|$$x]' ]
+}
+
+@test "Reverse like rev" {
+   run diff <(LC_ALL=C.UTF-8 rev README.md) <(./se -a 'v' README.md)
+   [ "$status" -eq 0 ]
+}
+
+@test "Change the case" {
+   run ./se 'U p L p' <<< 'Straße'
+   [ "$status" -eq 0 ]
+   [ "${lines[0]}" = 'STRASSE' ]
+   [ "${lines[1]}" = 'strasse' ]
+}
+
+@test "Line numbers with and without newline" {
+   run diff <(printf 'a\nb\n' | ./se '=p') <(printf '1\na\n2\nb\n')
+   [ "$status" -eq 0 ]
+   run diff <(printf 'a\nb\n' | ./se '=- ": " p') <(printf '1: a\n2: b\n')
+   [ "$status" -eq 0 ]
+}
+
+@test "Color the matches" {
+   run diff <(echo 'a cat' | ./se --color=always '/cat/ p') <(printf 'a \033[01;31m\033[Kcat\033[m\033[K\n')
+   [ "$status" -eq 0 ]
+   run diff <(echo 'a cat' | ./se --color=never '/cat/ p') <(echo 'a cat')
+   [ "$status" -eq 0 ]
+}
+
+@test "JSON lines" {
+   run diff <(printf 'say "hi"\tthere\n' | ./se --json p) <(printf '{"line": 1, "text": "say \\"hi\\"\\tthere"}\n')
+   [ "$status" -eq 0 ]
+   run bash -c "./se --json -a 'l' README.md | python3 -c 'import json, sys; [json.loads(l) for l in sys.stdin]'"
+   [ "$status" -eq 0 ]
+}
+
+@test "Count the matches by rule" {
+   run diff <(printf 'a\nb\nab\n' | ./se --count-by-rule '/a/ p ; /b/ ; 5 d' 2>&1 >/dev/null) <(printf '2\t/a/\n2\t/b/\n0\t5\n')
+   [ "$status" -eq 0 ]
+}
+
+@test "Initial hold space" {
+   run diff <(printf 'a\nb\n' | ./se --hold 'header' '1 x p') <(echo 'header')
+   [ "$status" -eq 0 ]
+
+   printf 'a\n' > /tmp/a.txt
+   printf 'b\n' > /tmp/b.txt
+   run diff <(./se --jobs 2 --hold 'header' '1 x p' /tmp/a.txt /tmp/b.txt) <(printf 'header\nheader\n')
+   [ "$status" -eq 0 ]
+   rm -f /tmp/a.txt /tmp/b.txt
+}
+
+@test "Warn about the unused addresses" {
+   run diff <(printf 'a\nb\n' | ./se --warn-unused '/a/ p ; /c/ d' 2>&1 >/dev/null) <(echo "warning: the address '/c/' did not match any line")
+   [ "$status" -eq 0 ]
+
+   run bash -c "printf 'a\n' | ./se --warn-unused '/c/ p'"
+   [ "$status" -eq 0 ]
+}
+
+@test "Finally runs at the end of input, but not after quitting" {
+   run diff <(printf '1\n2\n3\n' | ./se -a '$ "end\n"') <(printf '1\n2\n3\nend\n')
+   [ "$status" -eq 0 ]
+   run diff <(printf '1\n2\n3\n' | ./se -a '2 q ; $ "end\n"') <(printf '1\n2\n')
+   [ "$status" -eq 0 ]
+}
+
+@test "Quit from the finally block" {
+   run ./se '$ p q 3' <<< $'1\n2'
+   [ "$status" -eq 3 ]
+   [ "$output" = '2' ]
+}
+
+@test "Match the line length" {
+   run diff <(./se 'len>80 p' README.md) <(LC_ALL=C.UTF-8 grep -E '^.{81,}' README.md)
+   [ "$status" -eq 0 ]
+
+   run diff <(printf 'ab\nabc\nżółw\n' | ./se 'len<=3 p') <(printf 'ab\nabc\n')
+   [ "$status" -eq 0 ]
+}
+
+@test "Match a field" {
+   run diff <(printf 'a error\nerror b\nc\n' | ./se 'field(2)/error/ p') <(echo 'a error')
+   [ "$status" -eq 0 ]
+   run diff <(printf 'a:error\nerror:b\n' | ./se 'field(1 -d:)/error/ p') <(echo 'error:b')
+   [ "$status" -eq 0 ]
+}
+
+@test "Groups captured by the address" {
+   run diff <(printf 'a 12\nb\n' | ./se '/(\d+)/ "id=$1\n"') <(echo 'id=12')
+   [ "$status" -eq 0 ]
+}
+
+@test "List the files with matches" {
+   printf 'foo\nerror\nbar\n' > /tmp/a.txt
+   printf 'foo\nbar' > /tmp/b.txt
+   run diff <(./se --files-with-matches '/error/' /tmp/a.txt /tmp/b.txt) <(echo '/tmp/a.txt')
+   [ "$status" -eq 0 ]
+   run diff <(./se --files-with-matches '/foo/' /tmp/a.txt /tmp/b.txt) <(printf '/tmp/a.txt\n/tmp/b.txt\n')
+   [ "$status" -eq 0 ]
+}
+
+@test "Print the first line of the pattern space" {
+   run diff <(printf 'a\nb\nc\nd\n' | ./se 'r P') <(printf 'a\nc\n')
+   [ "$status" -eq 0 ]
+}
+
+@test "Script and files are not ambiguous" {
+   printf 'p\n' > /tmp/script.sed
+   printf 'a\n' > /tmp/a.txt
+   printf 'b\n' > /tmp/b.txt
+   run diff <(./se -f /tmp/script.sed /tmp/a.txt /tmp/b.txt) <(printf 'a\nb\n')
+   [ "$status" -eq 0 ]
+   run diff <(./se /tmp/a.txt -f /tmp/script.sed) <(printf 'a\n')
+   [ "$status" -eq 0 ]
+   run diff <(./se -e 'p' /tmp/a.txt /tmp/b.txt) <(printf 'a\nb\n')
+   [ "$status" -eq 0 ]
+   run diff <(./se -e '-1 p' /tmp/a.txt) <(printf 'a\n')
+   [ "$status" -eq 0 ]
+   run diff <(./se 'p' /tmp/a.txt /tmp/b.txt) <(printf 'a\nb\n')
+   [ "$status" -eq 0 ]
+   run diff <(./se -e '1 "x\n"' -f /tmp/script.sed -e 'p' /tmp/a.txt) <(printf 'x\na\na\n')
+   [ "$status" -eq 0 ]
+   run ./se -a
+   [ "$status" -ne 0 ]
+}
+
+@test "Read the script from stdin" {
+   printf 'a\nb\n' > /tmp/a.txt
+   run diff <(echo '2 p' | ./se -f - /tmp/a.txt) <(echo 'b')
+   [ "$status" -eq 0 ]
+   run bash -c "echo 'p' | ./se -f -"
+   [ "$status" -ne 0 ]
+}
+
+@test "Octal and hex escapes" {
+   run diff <(echo a | ./se '\101\x41\n "\102\x42\n" s/a/\103/ p') <(printf 'AA\nBB\nC\n')
+   [ "$status" -eq 0 ]
+   run ./se '"\xE9"' <<< a
+   [ "$status" -ne 0 ]
+}
+
+@test "Unicode escapes in braces" {
+   run diff <(echo a | ./se '\u{1F600}\n "\u{e9}\n" s/a/\u{41}/ p') <(printf '\360\237\230\200\n\303\251\nA\n')
+   [ "$status" -eq 0 ]
+   run ./se '"\u{D800}"' <<< a
+   [ "$status" -ne 0 ]
+   run ./se '"\u{110000}"' <<< a
+   [ "$status" -ne 0 ]
+}
+
+@test "Follow the appended lines" {
+   echo a > /tmp/a.txt
+   (sleep 0.5; echo b >> /tmp/a.txt) &
+   run timeout 5 ./se --follow -m 2 p /tmp/a.txt
+   [ "$status" -eq 0 ]
+   [ "$output" = $'a\nb' ]
+}
+
+@test "Parallel jobs keep the order of the files" {
+   seq 1 5 > /tmp/a.txt
+   seq 10 20 > /tmp/b.txt
+   seq 3 > /tmp/c.txt
+   run diff <(./se --jobs 2 -a '/1/ s/1/#/ =' /tmp/a.txt /tmp/b.txt /tmp/c.txt) \
+            <(for f in /tmp/a.txt /tmp/b.txt /tmp/c.txt; do ./se -a '/1/ s/1/#/ =' $f; done)
+   [ "$status" -eq 0 ]
+}
+
+@test "Parallel jobs stop at the file where the script quit" {
+   printf 'a\nb\n' > /tmp/a.txt
+   printf 'c\nd\n' > /tmp/b.txt
+   printf 'e\n' > /tmp/c.txt
+   run diff <(./se --jobs 2 'p ; 1 q 3' /tmp/a.txt /tmp/b.txt /tmp/c.txt) \
+            <(./se 'p ; 1 q 3' /tmp/a.txt /tmp/b.txt /tmp/c.txt)
+   [ "$status" -eq 0 ]
+   run ./se --jobs 2 'p ; 1 q 3' /tmp/a.txt /tmp/b.txt /tmp/c.txt
+   [ "$status" -eq 3 ]
+   [ "$output" = 'a' ]
+}
+
+@test "Memory-mapped files are read the same" {
+   printf 'a\r\nb\n\nc' > /tmp/a.txt
+   : > /tmp/b.txt
+   run diff <(./se -a '=' --mmap README.md /tmp/b.txt /tmp/a.txt) <(./se -a '=' README.md /tmp/b.txt /tmp/a.txt)
+   [ "$status" -eq 0 ]
+}
+
+@test "Write to files" {
+   rm -f /tmp/a.txt
+   echo 'old' > /tmp/b.txt
+   run ./se "w'/tmp/a.txt' ; /[13]/ w'/tmp/b.txt' w'/tmp/b.txt' ; /2/ w'/dev/stdout'" <<< $'1\n2\n3'
+   [ "$status" -eq 0 ]
+   [ "$output" = "2" ]
+   run diff /tmp/a.txt <(printf '1\n2\n3\n')
+   [ "$status" -eq 0 ]
+   run diff /tmp/b.txt <(printf '1\n1\n3\n3\n')
+   [ "$status" -eq 0 ]
+}
+
+@test "Write to stderr" {
+   run diff <(./se "w'/dev/stderr'" <<< 'a' 2>&1 >/dev/null) <(echo a)
+   [ "$status" -eq 0 ]
+}
+
+@test "Write the output to a file" {
+   printf 'a\nb\na\n' > /tmp/a.txt
+   rm -f /tmp/b.txt
+   run ./se -o /tmp/b.txt -c '/a/p' /tmp/a.txt
+   [ "$status" -eq 0 ]
+   [ "$output" = "" ]
+   run diff /tmp/b.txt <(printf 'a\na\n2\n')
+   [ "$status" -eq 0 ]
+   run ./se -o /tmp/a.txt 'p' /tmp/a.txt
+   [ "$status" -ne 0 ]
+}
+
+@test "Dotall and multiline flags" {
+   run diff <(printf 'a\nb\n' | ./se --dotall 'r s/a.b/x/ p') <(echo x)
+   [ "$status" -eq 0 ]
+   run diff <(printf 'a\nb\n' | ./se --multiline 'r s/^b/x/ p') <(printf 'a\nx\n')
+   [ "$status" -eq 0 ]
+   run diff <(printf 'a\nb\n' | ./se --dotall 'r s/(?-s)a.b/x/ p') <(printf 'a\nb\n')
+   [ "$status" -eq 0 ]
+}
+
+@test "Fixed strings" {
+   run diff <(printf 'a.b\naxb\n(x)\n' | ./se -F '/a.b/,^(x)$ s/./$1/ p') <(printf 'a$1b\n(x)\n')
+   [ "$status" -eq 0 ]
+}
+
+@test "Auto-print flags" {
+   run diff <(./se 'p' README.md) README.md
+   [ "$status" -eq 0 ]
+   run diff <(./se -a 'd' README.md) /dev/null
+   [ "$status" -eq 0 ]
+   run diff <(./se -n 'p' README.md) <(sed -n 'p' README.md)
+   [ "$status" -eq 0 ]
+   run diff <(./se -a -n 'p' README.md) README.md
+   [ "$status" -eq 0 ]
+   run diff <(./se -n -a 'p' README.md) <(sed 'p' README.md)
+   [ "$status" -eq 0 ]
+}
+
+@test "Match status" {
+   run ./se --match-status '/a/p' <<< 'a'
+   [ "$status" -eq 0 ]
+   run ./se --match-status '/x/p' <<< 'a'
+   [ "$status" -eq 1 ]
+   run ./se '/x/p' <<< 'a'
+   [ "$status" -eq 0 ]
+   run ./se --match-status '/x/p ; $q 3' <<< 'a'
+   [ "$status" -eq 3 ]
+   run ./se --match-status '/a/q 0' <<< 'a'
+   [ "$status" -eq 0 ]
+   run ./se --match-status --files-with-matches '/x/' <<< 'a'
+   [ "$status" -eq 1 ]
+   run ./se --match-status --files-with-matches '/a/' <<< 'a'
+   [ "$status" -eq 0 ]
+   [ "$output" = "-" ]
+}
+
+@test "Stdin among the files" {
+   printf 'a\nb\n' > /tmp/a.txt
+   run diff <(printf 'x\ny\n' | ./se 'F =p' /tmp/a.txt - /tmp/a.txt) <(printf '/tmp/a.txt\n1\na\n/tmp/a.txt\n2\nb\n-\n3\nx\n-\n4\ny\n/tmp/a.txt\n5\na\n/tmp/a.txt\n6\nb\n')
+   [ "$status" -eq 0 ]
+   run diff <(printf 'x\n' | ./se --files-with-matches '/x/' /tmp/a.txt -) <(echo -)
+   [ "$status" -eq 0 ]
+   run ./se -f - /tmp/a.txt - <<< 'p'
+   [ "$status" -ne 0 ]
+}
+
+@test "Read lines at the end of input" {
+   run diff <(printf '1\n2\n3\n' | ./se 'r ; s/\n/,/ ; p') <(printf '1,2\n3\n')
+   [ "$status" -eq 0 ]
+   run diff <(printf '1\n2\n3\n' | ./se -a 'R') <(printf '2\n3\n')
+   [ "$status" -eq 0 ]
+}
+
+@test "Expand tabs" {
+   run diff <(printf 'a\tb\nabc\td\n' | ./se 'E p') <(printf 'a       b\nabc     d\n')
+   [ "$status" -eq 0 ]
+   run diff <(printf 'a\tb\n' | ./se 'E 4 p') <(printf 'a   b\n')
+   [ "$status" -eq 0 ]
+   run ./se 'E 0' <<< 'a'
+   [ "$status" -ne 0 ]
+}
+
+@test "Number the lines" {
+   run diff <(./se 'n p' README.md) <(cat -n README.md)
+   [ "$status" -eq 0 ]
+   run diff <(printf 'a\nb\n' | ./se "n 3 -s': ' p") <(printf '  1: a\n  2: b\n')
+   [ "$status" -eq 0 ]
+}
+
+@test "Squeeze blank lines" {
+   run diff <(printf 'a\n\n\n\nb\n\n' | ./se -a 'B') <(printf 'a\n\nb\n\n')
+   [ "$status" -eq 0 ]
+   run diff <(./se -a 'B' README.md) <(cat -s README.md)
+   [ "$status" -eq 0 ]
+}
+
+@test "Comments in scripts" {
+   printf '# matching lines\n/a/, # the first\n/c/ # the last\np # print ; d\n' > /tmp/script.se
+   run diff <(printf 'a\nb\nc\n' | ./se -f /tmp/script.se) <(printf 'a\nc\n')
+   [ "$status" -eq 0 ]
+}
+
+@test "Substitution references missing groups" {
+   run ./se 's/(a)/$2/' <<< 'a'
+   [ "$status" -ne 0 ]
+   run ./se 's/(a)/${1}b/ p' <<< 'a'
+   [ "$status" -eq 0 ]
+   [ "$output" = "ab" ]
+}
+
+@test "Substitution flags" {
+   run diff <(printf 'a\nb\n' | ./se 'r ; s/^/> /m ; p') <(printf '> a\n> b\n')
+   [ "$status" -eq 0 ]
+   run diff <(printf 'A\nb\n' | ./se 's/a|B/x/gi ; p') <(printf 'x\nx\n')
+   [ "$status" -eq 0 ]
+}
+
+@test "Substitute from the Nth match" {
+   run diff <(echo 'aaaa' | ./se 's/a/X/2 ; p') <(echo 'XXaa')
+   [ "$status" -eq 0 ]
+   run diff <(echo 'aaaa' | ./se 's/a/X/2g ; p') <(echo 'aXXX')
+   [ "$status" -eq 0 ]
+   run diff <(echo 'aaaa' | ./se 's/a/X/2g ; p') <(echo 'aaaa' | sed 's/a/X/2g')
+   [ "$status" -eq 0 ]
+}
+
+@test "Read file and quit" {
+   echo 'appended' > /tmp/a.txt
+   run diff <(printf 'a\nx\nb\n' | ./se -a "/x/ r'/tmp/a.txt' q") <(printf 'a\nx\nappended\n')
+   [ "$status" -eq 0 ]
+}
+
+@test "Broken pipe" {
+   for i in $(seq 100); do cat README.md; done > /tmp/big.txt
+   run bash -c "./se -a '' /tmp/big.txt | head -n 1 > /dev/null; exit \${PIPESTATUS[0]}"
+   [ "$status" -eq 0 ]
+   [ "$output" = "" ]
+}
+
+@test "Inline data in the script file" {
+   printf '/b/ F p\n__END__\na\nb\n' > /tmp/script.se
+   run diff <(./se -f /tmp/script.se) <(printf '/tmp/script.se\nb\n')
+   [ "$status" -eq 0 ]
+   run diff <(echo 'b2' | ./se -f /tmp/script.se -) <(printf -- '-\nb2\n')
+   [ "$status" -eq 0 ]
+   run diff <(./se -f - <<< $'/b/ p\n__END__\nb3') <(echo b3)
+   [ "$status" -eq 0 ]
+}
+
+@test "Uniq" {
+   run diff <(printf 'a\na\nb\na\n' | ./se -a 'u') <(printf 'a\nb\na\n')
+   [ "$status" -eq 0 ]
+   run diff <(./se -a 'u' README.md) <(uniq README.md)
+   [ "$status" -eq 0 ]
+   run diff <(printf 'a 1\na 2\nb 3\n' | ./se -a 'u 1') <(printf 'a 1\nb 3\n')
+   [ "$status" -eq 0 ]
+}